@@ -0,0 +1,201 @@
+// Scoped context fields that the logger captures with every record
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+/// Ordered key/value fields. When a key repeats, the most recently added value wins.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Context {
+    fields: Vec<(String, String)>,
+}
+
+impl Context {
+    pub const fn new() -> Context {
+        Context { fields: Vec::new() }
+    }
+
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Context {
+        self.fields.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+
+    pub fn fields_mut(&mut self) -> &mut [(String, String)] {
+        &mut self.fields
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Snapshot of every field in scope on this thread and, inside a tokio task scope, this task.
+    /// Task fields are layered over thread fields.
+    pub fn current() -> Context {
+        let mut current = THREAD_CONTEXT.with(|scopes| {
+            let scopes = scopes.borrow();
+            let mut fields = Vec::with_capacity(scopes.iter().map(|s| s.fields.len()).sum());
+            for scope in scopes.iter() {
+                fields.extend(scope.fields.iter().cloned());
+            }
+            Context { fields }
+        });
+        let _ = TASK_CONTEXT.try_with(|task| current.fields.extend(task.fields.iter().cloned()));
+        current
+    }
+
+    fn extended(&self, other: Context) -> Context {
+        let mut fields = Vec::with_capacity(self.fields.len() + other.fields.len());
+        fields.extend(self.fields.iter().cloned());
+        fields.extend(other.fields);
+        Context { fields }
+    }
+}
+
+thread_local! {
+    // Stack of entered scopes, outermost first
+    static THREAD_CONTEXT: RefCell<Vec<Context>> = const { RefCell::new(Vec::new()) };
+}
+
+tokio::task_local! {
+    static TASK_CONTEXT: Context;
+}
+
+/// Keeps a thread scope entered with `enter` alive. The scope is exited when the guard drops,
+/// along with any scopes entered inside it that are still open.
+pub struct ContextGuard {
+    /// Scopes open when this one was entered
+    depth: usize,
+    // Scopes are per thread, so the guard must be dropped on the thread that created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        THREAD_CONTEXT.with(|scopes| scopes.borrow_mut().truncate(self.depth));
+    }
+}
+
+/// Add fields to every record sent from this thread until the returned guard is dropped
+pub fn enter(context: Context) -> ContextGuard {
+    let depth = THREAD_CONTEXT.with(|scopes| {
+        let mut scopes = scopes.borrow_mut();
+        scopes.push(context);
+        scopes.len() - 1
+    });
+    ContextGuard {
+        depth,
+        _not_send: PhantomData,
+    }
+}
+
+/// Add fields to every record sent from this thread while `f` runs
+pub fn scope<R>(context: Context, f: impl FnOnce() -> R) -> R {
+    let _guard = enter(context);
+    f()
+}
+
+/// Add fields to every record sent while `future` runs, following it across tokio worker threads.
+/// Nested task scopes include the fields of the enclosing task scope.
+pub async fn scope_task<F: Future>(context: Context, future: F) -> F::Output {
+    let context = TASK_CONTEXT
+        .try_with(|outer| outer.extended(context.clone()))
+        .unwrap_or(context);
+    TASK_CONTEXT.scope(context, future).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::Logger;
+    use crate::test_util::capture_publisher;
+
+    capture_publisher!(CapturePublisher, RECEIVED, u8);
+
+    #[test]
+    fn empty_outside_scope() {
+        assert!(Context::current().is_empty());
+    }
+
+    #[test]
+    fn nested_thread_scopes() {
+        let _outer = enter(Context::new().with("request_id", "r1").with("tenant", "t1"));
+        {
+            let _inner = enter(Context::new().with("tenant", "t2"));
+            let current = Context::current();
+            assert_eq!(current.get("request_id"), Some("r1"));
+            assert_eq!(current.get("tenant"), Some("t2"));
+            // Repeated keys are all kept, in the order they were added
+            let keys: Vec<&str> = current.fields().iter().map(|(k, _)| k.as_str()).collect();
+            assert_eq!(keys, vec!["request_id", "tenant", "tenant"]);
+        }
+        assert_eq!(Context::current().get("tenant"), Some("t1"));
+    }
+
+    #[test]
+    fn guards_dropped_out_of_order_exit_their_own_scope() {
+        let outer = enter(Context::new().with("tenant", "t1"));
+        let inner = enter(Context::new().with("user", "u1"));
+        // Exiting the outer scope exits the inner one too
+        drop(outer);
+        assert!(Context::current().is_empty());
+
+        let _other = enter(Context::new().with("request_id", "r1"));
+        drop(inner);
+        assert_eq!(Context::current().get("request_id"), Some("r1"));
+    }
+
+    #[test]
+    fn scope_exits_after_closure() {
+        scope(Context::new().with("user", "u1"), || {
+            assert_eq!(Context::current().get("user"), Some("u1"));
+        });
+        assert!(Context::current().is_empty());
+    }
+
+    #[tokio::test]
+    async fn task_scope_layers_over_thread_scope() {
+        let _guard = enter(Context::new().with("tenant", "t1").with("user", "u1"));
+        scope_task(Context::new().with("user", "u2"), async {
+            scope_task(Context::new().with("request_id", "r1"), async {
+                let current = Context::current();
+                assert_eq!(current.get("tenant"), Some("t1"));
+                assert_eq!(current.get("user"), Some("u2"));
+                assert_eq!(current.get("request_id"), Some("r1"));
+            })
+            .await;
+            assert_eq!(Context::current().get("request_id"), None);
+        })
+        .await;
+    }
+
+    #[test]
+    fn logger_delivers_context_captured_at_send() {
+        let logger: Logger<u8> = Logger::new::<CapturePublisher>();
+        scope(Context::new().with("request_id", "r1"), || {
+            logger.send(1).unwrap();
+        });
+        logger.send(2).unwrap();
+        logger.close();
+
+        let received = RECEIVED.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0, 1);
+        assert_eq!(received[0].1.get("request_id"), Some("r1"));
+        assert_eq!(received[1].0, 2);
+        assert!(received[1].1.is_empty());
+    }
+}
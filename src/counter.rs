@@ -1,126 +1,1602 @@
 // Adapt the generic logger for use as a count publisher
 
-use std::collections::HashMap;
+use std::collections::{btree_map, BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use futures::SinkExt;
 use tokio::net::TcpStream;
 use tokio_serde::formats::SymmetricalJson;
 use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
 
-use crate::counter_types::{get_epoc_minutes, CounterMessage, CounterState, CounterUpdateMessage};
+use crate::counter_config::CounterConfig;
+use crate::counter_types::{
+    get_epoc_seconds, BatchEntry, BatchMessage, CounterKey, CounterMessage, CounterState,
+    GaugeState, HistogramState, IntervalState, MetricRef,
+};
+use crate::error::Error;
+use crate::global::GlobalCell;
 use crate::logger::{Logger, Publisher};
 
-struct CountersStruct(Logger<String>);
+struct CountersStruct(Logger<MetricRecord>);
 static COUNTERS: CountersStruct = CountersStruct(Logger::new::<CounterPublishState>());
+static STATUS: PublisherStatus = PublisherStatus::new();
+static CONFIG: GlobalCell<CounterConfig> = GlobalCell::new();
+/// How long closing the publisher waits for unpublished counts to reach the server
+static SHUTDOWN_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_SHUTDOWN_TIMEOUT.as_millis() as u64);
+
+/// Most (counter, interval) pairs held while the server is unreachable
+const MAX_PENDING_UPDATES: usize = 10_000;
+/// Most (metric, interval) states sent in one batch frame
+const MAX_BATCH_STATES: usize = 1_000;
+/// Most recent updates kept to resend after reconnecting
+const MAX_RESEND_UPDATES: usize = 32;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How soon after an interval closes its final counts are sent
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Nothing has been published yet
+    Idle,
+    Connected,
+    /// The last attempt to reach the server failed. Updates are buffered until it is back.
+    Disconnected,
+}
+
+/// Connection health shared between the publisher thread and callers
+struct PublisherStatus {
+    status: AtomicU8,
+    pending: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl PublisherStatus {
+    const fn new() -> PublisherStatus {
+        PublisherStatus {
+            status: AtomicU8::new(ConnectionStatus::Idle as u8),
+            pending: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn set(&self, status: ConnectionStatus) {
+        self.status.store(status as u8, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> ConnectionStatus {
+        match self.status.load(Ordering::Relaxed) {
+            s if s == ConnectionStatus::Connected as u8 => ConnectionStatus::Connected,
+            s if s == ConnectionStatus::Disconnected as u8 => ConnectionStatus::Disconnected,
+            _ => ConnectionStatus::Idle,
+        }
+    }
+}
+
+// Keys are shared so a `Counter` handle can send them without allocating
+enum MetricRecord {
+    Increment {
+        counter: Arc<CounterKey>,
+        amount: u64,
+    },
+    SetGauge {
+        gauge: Arc<CounterKey>,
+        value: f64,
+    },
+    Record {
+        histogram: Arc<CounterKey>,
+        value: f64,
+    },
+}
 
 struct CounterPublishState {
-    counters: HashMap<String, CounterState>,
+    counters: HashMap<Arc<CounterKey>, LocalCounterState>,
+    gauges: HashMap<Arc<CounterKey>, LocalSamples<GaugeState>>,
+    histograms: HashMap<Arc<CounterKey>, LocalSamples<HistogramState>>,
+    connection: CounterConnection,
+}
+
+/// The running count for the current interval and how much of it the server has been sent
+struct LocalCounterState {
+    current: CounterState,
+    published: u64,
+}
+
+impl LocalCounterState {
+    /// The count not yet sent to the server, marking it as sent
+    fn take_delta(&mut self) -> Option<CounterState> {
+        let delta = self.current.count - self.published;
+        self.published = self.current.count;
+        (delta > 0).then_some(CounterState {
+            epoch_seconds: self.current.epoch_seconds,
+            count: delta,
+        })
+    }
+}
+
+/// The current interval's gauge or histogram samples not yet sent to the server
+struct LocalSamples<S> {
+    epoch_seconds: u64,
+    samples: u64,
+    unpublished: Option<S>,
+}
+
+/// States built up from individual samples
+trait Sampled: Buffered {
+    fn new(epoch_seconds: u64, value: f64) -> Self;
+    fn record(&mut self, value: f64);
+}
+
+impl Sampled for GaugeState {
+    fn new(epoch_seconds: u64, value: f64) -> Self {
+        GaugeState::new(epoch_seconds, value)
+    }
+
+    fn record(&mut self, value: f64) {
+        GaugeState::record(self, value);
+    }
+}
+
+impl Sampled for HistogramState {
+    fn new(epoch_seconds: u64, value: f64) -> Self {
+        HistogramState::new(epoch_seconds, value)
+    }
+
+    fn record(&mut self, value: f64) {
+        HistogramState::record(self, value);
+    }
 }
 
 impl CounterPublishState {
     fn publish_to_remote(
-        &self,
-        counter: String,
-        prev_counter_state: Option<CounterState>,
-        cur_counter_state: CounterState,
-    ) {
-        let mut state = Vec::with_capacity(2);
-        if let Some(prev_cs) = prev_counter_state {
-            state.push(prev_cs);
-        }
-        state.push(cur_counter_state);
-        let message = CounterUpdateMessage { counter, state };
-
-        // TODO: Maintain just one connection (or pool or thread)
-
-        tokio::runtime::Builder::new_current_thread()
-            .enable_time()
-            .enable_io()
-            .build()
-            .unwrap()
-            .block_on(transport_message(CounterMessage::Update(message)))
-            .unwrap();
+        &mut self,
+        counter: &CounterKey,
+        prev_delta: Option<CounterState>,
+        cur_delta: Option<CounterState>,
+    ) -> Result<(), Error> {
+        let state: Vec<CounterState> = prev_delta.into_iter().chain(cur_delta).collect();
+        if state.is_empty() {
+            return Ok(());
+        }
+
+        self.connection.publish(counter.clone(), state)
+    }
+}
+
+type CounterSink = tokio_serde::SymmetricallyFramed<
+    FramedWrite<TcpStream, LengthDelimitedCodec>,
+    CounterMessage,
+    SymmetricalJson<CounterMessage>,
+>;
+
+/// One framed connection to the counter server, kept open across publishes
+/// and re-established when a write fails.
+/// While the server is unreachable, updates are buffered and reconnects back off exponentially.
+struct CounterConnection {
+    config: CounterConfig,
+    // The publisher thread is synchronous, so it drives the connection on its own runtime
+    runtime: tokio::runtime::Runtime,
+    sink: Option<CounterSink>,
+    /// Unsent counts and samples
+    counts: Pending<CounterState>,
+    gauges: Pending<GaugeState>,
+    histograms: Pending<HistogramState>,
+    /// Identifies this process's updates to the server
+    source: u64,
+    next_seq: u64,
+    /// An update whose send failed. It may have reached the server, so it is retried
+    /// with the same sequence number for the server to discard if it did.
+    unsent: Option<CounterMessage>,
+    /// The latest updates written to the open connection, oldest first.
+    /// A write into a connection the server already closed can succeed locally and still be lost,
+    /// so these are resent on the next connection for the server to discard any it has.
+    sent: VecDeque<CounterMessage>,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    /// Sends give up at this time, set while shutting down
+    deadline: Option<Instant>,
+    /// Ids registered with the server on the open connection
+    ids: HashMap<CounterKey, u32>,
+    status: &'static PublisherStatus,
+}
+
+impl CounterConnection {
+    fn new(config: CounterConfig, status: &'static PublisherStatus) -> CounterConnection {
+        CounterConnection {
+            config,
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .enable_io()
+                .build()
+                .expect("Failed to build counter publisher runtime"),
+            sink: None,
+            counts: Pending::default(),
+            gauges: Pending::default(),
+            histograms: Pending::default(),
+            source: rand::random(),
+            next_seq: 1,
+            unsent: None,
+            sent: VecDeque::new(),
+            backoff: INITIAL_BACKOFF,
+            retry_at: None,
+            deadline: None,
+            ids: HashMap::new(),
+            status,
+        }
+    }
+
+    /// Buffer the update. It is sent with the others buffered alongside it once the
+    /// logger's queue drains or a tick comes, or right away when a full batch is pending.
+    /// On failure the updates stay buffered for the next attempt.
+    fn publish<S: Buffered>(&mut self, counter: CounterKey, deltas: Vec<S>) -> Result<(), Error> {
+        self.buffer(counter, deltas);
+        if self.pending_len() >= MAX_BATCH_STATES {
+            return self.flush_if_due();
+        }
+        Ok(())
+    }
+
+    /// Send anything buffered, unless waiting out a backoff
+    fn flush_if_due(&mut self) -> Result<(), Error> {
+        if self.pending_len() == 0 && self.unsent.is_none() {
+            return Ok(());
+        }
+        if self
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    fn buffer<S: Buffered>(&mut self, counter: CounterKey, deltas: Vec<S>) {
+        S::pending(self).add(counter, deltas);
+
+        while self.pending_len() > MAX_PENDING_UPDATES {
+            self.drop_oldest_pending();
+        }
+        self.update_pending_status();
+    }
+
+    fn pending_len(&self) -> usize {
+        self.counts.len + self.gauges.len + self.histograms.len
+    }
+
+    fn update_pending_status(&self) {
+        let unsent_len = match &self.unsent {
+            Some(CounterMessage::Update(unsent)) => unsent.state.len(),
+            Some(CounterMessage::UpdateGauge(unsent)) => unsent.state.len(),
+            Some(CounterMessage::UpdateHistogram(unsent)) => unsent.state.len(),
+            Some(CounterMessage::Batch(unsent)) => unsent.len(),
+            _ => 0,
+        };
+        self.status
+            .pending
+            .store(self.pending_len() + unsent_len, Ordering::Relaxed);
+    }
+
+    fn drop_oldest_pending(&mut self) {
+        let counts = self.counts.oldest().map(|(epoch_seconds, _)| epoch_seconds);
+        let gauges = self.gauges.oldest().map(|(epoch_seconds, _)| epoch_seconds);
+        let histograms = self
+            .histograms
+            .oldest()
+            .map(|(epoch_seconds, _)| epoch_seconds);
+        let oldest = counts.into_iter().chain(gauges).chain(histograms).min();
+        let dropped = if oldest.is_none() {
+            false
+        } else if counts == oldest {
+            self.counts.drop_oldest()
+        } else if gauges == oldest {
+            self.gauges.drop_oldest()
+        } else {
+            self.histograms.drop_oldest()
+        };
+        if dropped {
+            self.status.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The buffered updates as one batch, given a sequence number
+    fn next_pending(&mut self) -> Option<CounterMessage> {
+        let mut batch = BatchMessage::default();
+        let mut states = 0;
+        self.counts.pop_into(&mut batch.counters, &mut states);
+        self.gauges.pop_into(&mut batch.gauges, &mut states);
+        self.histograms.pop_into(&mut batch.histograms, &mut states);
+        if batch.is_empty() {
+            return None;
+        }
+
+        batch.source = self.source;
+        batch.seq = self.next_seq;
+        self.next_seq += 1;
+        Some(CounterMessage::Batch(batch))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        loop {
+            let message = match self.unsent.take() {
+                Some(unsent) => unsent,
+                None => match self.next_pending() {
+                    Some(message) => message,
+                    None => break,
+                },
+            };
+
+            if let Err(e) = self.send(message.clone()) {
+                self.unsent = Some(message);
+                self.update_pending_status();
+                self.retry_at = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                self.status.set(ConnectionStatus::Disconnected);
+                return Err(e);
+            }
+            self.update_pending_status();
+        }
+
+        self.retry_at = None;
+        self.backoff = INITIAL_BACKOFF;
+        self.status.set(ConnectionStatus::Connected);
+        Ok(())
+    }
+
+    /// Send everything buffered, retrying with backoff until the deadline
+    fn flush_until(&mut self, deadline: Instant) -> Result<(), Error> {
+        self.deadline = Some(deadline);
+        loop {
+            let result = self.flush();
+            let now = Instant::now();
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if now >= deadline => return Err(e),
+                Err(_) => std::thread::sleep(self.backoff.min(deadline - now)),
+            }
+        }
+    }
+
+    fn send(&mut self, message: CounterMessage) -> Result<(), Error> {
+        let CounterConnection {
+            config,
+            runtime,
+            sink,
+            sent,
+            deadline,
+            ids,
+            ..
+        } = self;
+        let result = runtime.block_on(async {
+            let attempt = async {
+                if let Some(open_sink) = sink.as_mut() {
+                    match write_message(open_sink, ids, message.clone()).await {
+                        Ok(()) => return Ok(()),
+                        // The server closed the connection, usually only seen on a later write.
+                        // Reconnect and retry once.
+                        Err(_) => *sink = None,
+                    }
+                }
+
+                // Registrations don't carry over to a new connection
+                ids.clear();
+                let mut new_sink = connect(config).await?;
+                // Earlier writes may have been lost with the old connection
+                for resend in sent.iter() {
+                    write_message(&mut new_sink, ids, resend.clone()).await?;
+                }
+                write_message(&mut new_sink, ids, message.clone()).await?;
+                *sink = Some(new_sink);
+                Ok(())
+            };
+            // A server that stops accepting or reading would otherwise stall the publisher thread
+            let timeout_at = Instant::now() + config.send_timeout();
+            let (timeout_at, reason) = match deadline {
+                Some(deadline) if *deadline < timeout_at => (
+                    *deadline,
+                    "counter server did not respond before the deadline",
+                ),
+                _ => (
+                    timeout_at,
+                    "counter server did not respond within the send timeout",
+                ),
+            };
+            tokio::time::timeout_at(timeout_at.into(), attempt)
+                .await
+                .map_err(|_| Error::Transport(io::Error::new(io::ErrorKind::TimedOut, reason)))?
+        });
+        if matches!(&result, Err(Error::Transport(e)) if e.kind() == io::ErrorKind::TimedOut) {
+            // A frame may have been partly written
+            *sink = None;
+        }
+        let is_update = matches!(
+            message,
+            CounterMessage::Update(_)
+                | CounterMessage::UpdateGauge(_)
+                | CounterMessage::UpdateHistogram(_)
+                | CounterMessage::Batch(_)
+        );
+        if result.is_ok() && is_update {
+            if sent.len() == MAX_RESEND_UPDATES {
+                sent.pop_front();
+            }
+            sent.push_back(message);
+        }
+        result
+    }
+}
+
+/// Unsent states by metric, then interval.
+/// States for the same metric and interval are merged, so the buffer grows with time, not updates.
+struct Pending<S> {
+    states: BTreeMap<CounterKey, BTreeMap<u64, S>>,
+    len: usize,
+}
+
+impl<S> Default for Pending<S> {
+    fn default() -> Self {
+        Pending {
+            states: BTreeMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<S: IntervalState> Pending<S> {
+    fn add(&mut self, counter: CounterKey, deltas: Vec<S>) {
+        let intervals = self.states.entry(counter).or_default();
+        for delta in deltas {
+            match intervals.entry(delta.epoch_seconds()) {
+                btree_map::Entry::Occupied(mut entry) => entry.get_mut().merge(delta),
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(delta);
+                    self.len += 1;
+                }
+            }
+        }
+    }
+
+    fn oldest(&self) -> Option<(u64, CounterKey)> {
+        self.states
+            .iter()
+            .filter_map(|(counter, intervals)| {
+                intervals
+                    .keys()
+                    .next()
+                    .map(|epoch_seconds| (*epoch_seconds, counter.clone()))
+            })
+            .min()
+    }
+
+    fn drop_oldest(&mut self) -> bool {
+        let Some((epoch_seconds, counter)) = self.oldest() else {
+            return false;
+        };
+        let intervals = self.states.get_mut(&counter).unwrap();
+        intervals.remove(&epoch_seconds);
+        if intervals.is_empty() {
+            self.states.remove(&counter);
+        }
+        self.len -= 1;
+        true
+    }
+
+    /// Move whole metrics into the batch entries until it holds at least `MAX_BATCH_STATES`
+    fn pop_into(&mut self, entries: &mut Vec<BatchEntry<S>>, states: &mut usize) {
+        while *states < MAX_BATCH_STATES {
+            let Some((counter, intervals)) = self.states.pop_first() else {
+                return;
+            };
+            self.len -= intervals.len();
+            *states += intervals.len();
+            entries.push(BatchEntry {
+                counter: counter.into(),
+                state: intervals.into_values().collect(),
+            });
+        }
+    }
+}
+
+/// Metric states the connection can buffer
+trait Buffered: IntervalState {
+    fn pending(connection: &mut CounterConnection) -> &mut Pending<Self>;
+}
+
+impl Buffered for CounterState {
+    fn pending(connection: &mut CounterConnection) -> &mut Pending<Self> {
+        &mut connection.counts
     }
 }
 
-async fn transport_message(message: CounterMessage) -> Result<(), std::io::Error> {
-    let socket = TcpStream::connect("127.0.0.1:7878").await.unwrap();
+impl Buffered for GaugeState {
+    fn pending(connection: &mut CounterConnection) -> &mut Pending<Self> {
+        &mut connection.gauges
+    }
+}
+
+impl Buffered for HistogramState {
+    fn pending(connection: &mut CounterConnection) -> &mut Pending<Self> {
+        &mut connection.histograms
+    }
+}
 
-    let length_delimited = FramedWrite::new(socket, LengthDelimitedCodec::new());
+/// Write the message, sending updates by id and registering ids the connection hasn't seen
+async fn write_message(
+    sink: &mut CounterSink,
+    ids: &mut HashMap<CounterKey, u32>,
+    message: CounterMessage,
+) -> Result<(), Error> {
+    let mut message = message;
+    match &mut message {
+        CounterMessage::Update(update) => intern(sink, ids, &mut update.counter).await?,
+        CounterMessage::UpdateGauge(update) => intern(sink, ids, &mut update.counter).await?,
+        CounterMessage::UpdateHistogram(update) => intern(sink, ids, &mut update.counter).await?,
+        CounterMessage::Batch(batch) => {
+            for entry in batch.counters.iter_mut() {
+                intern(sink, ids, &mut entry.counter).await?;
+            }
+            for entry in batch.gauges.iter_mut() {
+                intern(sink, ids, &mut entry.counter).await?;
+            }
+            for entry in batch.histograms.iter_mut() {
+                intern(sink, ids, &mut entry.counter).await?;
+            }
+        }
+        _ => {}
+    }
+    sink.send(message).await?;
+    Ok(())
+}
 
-    let mut serialized = tokio_serde::SymmetricallyFramed::new(
+async fn intern(
+    sink: &mut CounterSink,
+    ids: &mut HashMap<CounterKey, u32>,
+    counter: &mut MetricRef,
+) -> Result<(), Error> {
+    if let MetricRef::Key(key) = counter {
+        let id = match ids.get(key) {
+            Some(id) => *id,
+            None => {
+                let id = ids.len() as u32 + 1;
+                // Buffered, so it goes out in the same write as the update
+                sink.feed(CounterMessage::Register {
+                    id,
+                    counter: key.clone(),
+                })
+                .await?;
+                ids.insert(key.clone(), id);
+                id
+            }
+        };
+        *counter = MetricRef::Id(id);
+    }
+    Ok(())
+}
+
+async fn connect(config: &CounterConfig) -> Result<CounterSink, Error> {
+    let length_delimited =
+        FramedWrite::new(connect_stream(config).await?, LengthDelimitedCodec::new());
+
+    Ok(tokio_serde::SymmetricallyFramed::new(
         length_delimited,
         SymmetricalJson::<CounterMessage>::default(),
-    );
+    ))
+}
 
-    serialized.send(message).await
+/// A socket to the first of the server's addresses that accepts
+pub(crate) async fn connect_stream(config: &CounterConfig) -> Result<TcpStream, Error> {
+    // Resolve on every connect so a moved host is picked up, then try each address in turn
+    let mut last_error = None;
+    let mut socket = None;
+    for addr in config.resolve().await? {
+        match TcpStream::connect(addr).await {
+            Ok(connected) => {
+                socket = Some(connected);
+                break;
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let socket = match socket {
+        Some(socket) => socket,
+        None => return Err(last_error.map_or(Error::Closed, Error::Transport)),
+    };
+    socket.set_nodelay(true)?;
+    Ok(socket)
 }
 
-impl Publisher<String> for CounterPublishState {
+impl Publisher<MetricRecord> for CounterPublishState {
     fn new() -> Self {
         CounterPublishState {
             counters: HashMap::new(),
+            gauges: HashMap::new(),
+            histograms: HashMap::new(),
+            connection: CounterConnection::new(client_config().as_ref().clone(), &STATUS),
         }
     }
 
-    fn send(&mut self, counter: String) -> Result<(), ()> {
-        // ~1 minute accuracy
-        let epoch_minutes = get_epoc_minutes();
+    fn send(&mut self, record: MetricRecord) -> Result<(), Error> {
+        match record {
+            MetricRecord::Increment { counter, amount } => self.increment(counter, amount),
+            MetricRecord::SetGauge { gauge, value } => {
+                record_sample(&mut self.gauges, &mut self.connection, gauge, value)
+            }
+            MetricRecord::Record { histogram, value } => {
+                record_sample(&mut self.histograms, &mut self.connection, histogram, value)
+            }
+        }
+    }
 
-        let mut prev_cs: Option<CounterState> = None;
-        let cur_count = if let Some(cs) = self.counters.get_mut(&counter) {
-            if cs.epoch_minutes == epoch_minutes {
-                cs.count += 1;
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(FLUSH_INTERVAL)
+    }
 
-                cs.count
-            } else {
-                prev_cs = Some(std::mem::replace(
-                    cs,
-                    CounterState {
-                        epoch_minutes,
-                        count: 1,
-                    },
-                ));
+    /// Send the rest of any interval that has closed, and retry buffered updates after a backoff
+    fn tick(&mut self) -> Result<(), Error> {
+        self.take_unpublished(current_interval(&self.connection.config));
+        self.connection.flush_if_due()
+    }
 
-                1
-            }
-        } else {
-            self.counters.insert(
-                counter.clone(),
-                CounterState {
-                    epoch_minutes,
-                    count: 1,
+    /// Send what the records handled since the last flush buffered, as one batch
+    fn drained(&mut self) -> Result<(), Error> {
+        self.connection.flush_if_due()
+    }
+}
+
+impl CounterPublishState {
+    fn increment(&mut self, counter: Arc<CounterKey>, amount: u64) -> Result<(), Error> {
+        // Accurate to the report interval
+        let epoch_seconds = current_interval(&self.connection.config);
+
+        let mut prev_delta: Option<CounterState> = None;
+        let local = self
+            .counters
+            .entry(counter.clone())
+            .or_insert_with(|| LocalCounterState {
+                current: CounterState {
+                    epoch_seconds,
+                    count: 0,
                 },
-            );
+                published: 0,
+            });
+        let prev_count = if local.current.epoch_seconds == epoch_seconds {
+            local.current.count
+        } else {
+            // Whatever the last interval didn't publish goes out with this update
+            prev_delta = local.take_delta();
+            local.current = CounterState {
+                epoch_seconds,
+                count: 0,
+            };
+            local.published = 0;
 
-            1
+            0
         };
+        local.current.count = local.current.count.saturating_add(amount);
 
-        // Publish if it has been a minute or the count is a power of 2
-        // TODO: Start at the power of two from the last minute or 1/2 of it
-        if prev_cs.is_some() || cur_count == cur_count.next_power_of_two() {
-            self.publish_to_remote(
-                counter,
-                prev_cs,
-                CounterState {
-                    epoch_minutes,
-                    count: cur_count,
-                },
-            );
+        // Publish if it has been an interval or the count reached a new power of 2
+        // TODO: Start at the power of two from the last interval or 1/2 of it
+        if prev_delta.is_some() || crosses_power_of_two(prev_count, local.current.count) {
+            let cur_delta = local.take_delta();
+            self.publish_to_remote(&counter, prev_delta, cur_delta)?;
         }
 
         Ok(())
     }
 }
 
+fn take_unpublished_samples<S: Sampled>(
+    locals: &mut HashMap<Arc<CounterKey>, LocalSamples<S>>,
+    connection: &mut CounterConnection,
+    before_seconds: u64,
+) {
+    for (key, local) in locals.iter_mut() {
+        if local.epoch_seconds < before_seconds {
+            if let Some(state) = local.unpublished.take() {
+                connection.buffer(CounterKey::clone(key), vec![state]);
+            }
+        }
+    }
+}
+
+fn record_sample<S: Sampled>(
+    locals: &mut HashMap<Arc<CounterKey>, LocalSamples<S>>,
+    connection: &mut CounterConnection,
+    key: Arc<CounterKey>,
+    value: f64,
+) -> Result<(), Error> {
+    let epoch_seconds = current_interval(&connection.config);
+
+    let mut prev_state: Option<S> = None;
+    let local = locals.entry(key.clone()).or_insert_with(|| LocalSamples {
+        epoch_seconds,
+        samples: 0,
+        unpublished: None,
+    });
+    if local.epoch_seconds != epoch_seconds {
+        // Whatever the last interval didn't publish goes out with this update
+        prev_state = local.unpublished.take();
+        local.epoch_seconds = epoch_seconds;
+        local.samples = 0;
+    }
+    match local.unpublished.as_mut() {
+        Some(state) => state.record(value),
+        None => local.unpublished = Some(S::new(epoch_seconds, value)),
+    }
+    let prev_samples = local.samples;
+    local.samples += 1;
+
+    // Like counts, publish on a new interval or when the number of samples reaches a power of 2
+    if prev_state.is_some() || crosses_power_of_two(prev_samples, local.samples) {
+        let state: Vec<S> = prev_state
+            .into_iter()
+            .chain(local.unpublished.take())
+            .collect();
+        connection.publish(CounterKey::clone(&key), state)?;
+    }
+
+    Ok(())
+}
+
+/// The start of the report interval the current time falls in, in epoch seconds
+fn current_interval(config: &CounterConfig) -> u64 {
+    let epoch_seconds = get_epoc_seconds();
+    epoch_seconds - epoch_seconds % config.report_interval_seconds
+}
+
+/// Whether some power of two is in (prev_count, cur_count].
+/// With increments of 1 this is cur_count being a power of two, and a large jump publishes once.
+fn crosses_power_of_two(prev_count: u64, cur_count: u64) -> bool {
+    prev_count
+        .checked_add(1)
+        .and_then(u64::checked_next_power_of_two)
+        .is_some_and(|next_power| cur_count >= next_power)
+}
+
+impl CounterPublishState {
+    /// Buffer everything recorded but not yet sent from intervals before `before_seconds`
+    fn take_unpublished(&mut self, before_seconds: u64) {
+        for (counter, local) in self.counters.iter_mut() {
+            if local.current.epoch_seconds < before_seconds {
+                if let Some(delta) = local.take_delta() {
+                    self.connection
+                        .buffer(CounterKey::clone(counter), vec![delta]);
+                }
+            }
+        }
+        take_unpublished_samples(&mut self.gauges, &mut self.connection, before_seconds);
+        take_unpublished_samples(&mut self.histograms, &mut self.connection, before_seconds);
+    }
+}
+
 impl Drop for CounterPublishState {
+    // Runs on the publisher thread once the logger is closed and every queued record is handled
     fn drop(&mut self) {
-        if self.counters.values().any(|c| c.count > 0) {
-            // panic!("Some counters not published");
+        self.take_unpublished(u64::MAX);
+        let timeout = Duration::from_millis(SHUTDOWN_TIMEOUT_MS.load(Ordering::Relaxed));
+        if let Err(e) = self.connection.flush_until(Instant::now() + timeout) {
+            eprintln!(
+                "{} counter updates were not published before shutdown: {}",
+                self.connection.status.pending.load(Ordering::Relaxed),
+                e
+            );
         }
     }
 }
 
-pub fn inc_counter(counter: String) {
-    COUNTERS.0.send(counter).unwrap();
+/// Set where counts are published. Must be called before the first increment.
+/// Without it, the config comes from `CounterConfig::from_env`.
+pub fn configure(config: CounterConfig) -> Result<(), CounterConfig> {
+    CONFIG.set(config)
+}
+
+fn client_config() -> std::sync::Arc<CounterConfig> {
+    CONFIG.get_or_init(|| {
+        CounterConfig::from_env().unwrap_or_else(|e| {
+            eprintln!("Using the default counter server config: {}", e);
+            CounterConfig::default()
+        })
+    })
+}
+
+/// Queue an increment for the background publisher.
+/// Failures to reach the counter server are reported by `take_publish_error`.
+/// Takes a plain name, or a `CounterKey` with labels, ie.
+/// `CounterKey::new("requests").label("status", "500")`.
+pub fn inc_counter(counter: impl Into<CounterKey>) -> Result<(), Error> {
+    inc_counter_by(counter, 1)
+}
+
+/// Add `amount` to a counter, ie. bytes written or rows processed, in one message
+pub fn inc_counter_by(counter: impl Into<CounterKey>, amount: u64) -> Result<(), Error> {
+    if amount == 0 {
+        return Ok(());
+    }
+    COUNTERS.0.send(MetricRecord::Increment {
+        counter: Arc::new(counter.into()),
+        amount,
+    })
+}
+
+/// A counter that increments without allocating, ie. from a static.
+/// `counter!("name")` declares one in place.
+pub struct Counter {
+    name: &'static str,
+    key: OnceLock<Arc<CounterKey>>,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Counter {
+        Counter {
+            name,
+            key: OnceLock::new(),
+        }
+    }
+
+    /// A handle for a counter with labels
+    pub fn labeled(key: CounterKey) -> Counter {
+        Counter {
+            name: "",
+            key: OnceLock::from(Arc::new(key)),
+        }
+    }
+
+    pub fn inc(&self) -> Result<(), Error> {
+        self.inc_by(1)
+    }
+
+    pub fn inc_by(&self, amount: u64) -> Result<(), Error> {
+        if amount == 0 {
+            return Ok(());
+        }
+        COUNTERS.0.send(MetricRecord::Increment {
+            counter: self.key().clone(),
+            amount,
+        })
+    }
+
+    fn key(&self) -> &Arc<CounterKey> {
+        self.key
+            .get_or_init(|| Arc::new(CounterKey::new(self.name)))
+    }
+}
+
+/// A static `Counter` for the name, ie. `counter!("requests").inc()`
+#[macro_export]
+macro_rules! counter {
+    ($name:expr) => {{
+        static COUNTER: $crate::counter::Counter = $crate::counter::Counter::new($name);
+        &COUNTER
+    }};
+}
+
+/// Record the current value of a gauge, ie. queue depth or memory use.
+/// The server keeps the last, min, max, sum and number of values set in each interval.
+/// NaN and infinite values are rejected with `Error::InvalidValue`.
+pub fn set_gauge(gauge: impl Into<CounterKey>, value: f64) -> Result<(), Error> {
+    if !value.is_finite() {
+        return Err(Error::InvalidValue(format!(
+            "gauge value {} is not finite",
+            value
+        )));
+    }
+    COUNTERS.0.send(MetricRecord::SetGauge {
+        gauge: Arc::new(gauge.into()),
+        value,
+    })
+}
+
+/// Send every unpublished count and sample to the server, then stop the publisher.
+/// Call before the process exits, since statics are never dropped.
+/// Later increments fail with `Error::Closed`.
+pub fn shutdown() -> Result<(), Error> {
+    shutdown_timeout(DEFAULT_SHUTDOWN_TIMEOUT)
+}
+
+/// `shutdown`, waiting at most `timeout` for the server
+pub fn shutdown_timeout(timeout: Duration) -> Result<(), Error> {
+    SHUTDOWN_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    // Joins the publisher thread, which publishes as the publisher is dropped
+    COUNTERS.0.close();
+    match pending_updates() {
+        0 => Ok(()),
+        unpublished => Err(Error::PublisherFailed(format!(
+            "{} counter updates were not published before shutdown",
+            unpublished
+        ))),
+    }
+}
+
+/// Add a sample to a histogram, ie. a request latency.
+/// The server can report percentiles of the samples over any range of time.
+/// NaN and infinite samples are rejected with `Error::InvalidValue`.
+pub fn record_histogram(histogram: impl Into<CounterKey>, value: f64) -> Result<(), Error> {
+    if !value.is_finite() {
+        return Err(Error::InvalidValue(format!(
+            "histogram sample {} is not finite",
+            value
+        )));
+    }
+    COUNTERS.0.send(MetricRecord::Record {
+        histogram: Arc::new(histogram.into()),
+        value,
+    })
+}
+
+/// Run the block, recording how long it took in milliseconds to the histogram
+pub fn time_block<R>(histogram: impl Into<CounterKey>, block: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let result = block();
+    // Timing never fails the block. A closed publisher only loses the sample.
+    let _ = record_histogram(histogram, start.elapsed().as_secs_f64() * 1000.0);
+    result
+}
+
+/// The most recent error the background publisher hit sending counts to the server
+pub fn take_publish_error() -> Option<Error> {
+    COUNTERS.0.take_publisher_error()
+}
+
+pub fn connection_status() -> ConnectionStatus {
+    STATUS.get()
+}
+
+/// Number of (counter, interval) counts waiting for the server to be reachable
+pub fn pending_updates() -> usize {
+    STATUS.pending.load(Ordering::Relaxed)
+}
+
+/// Number of buffered counts discarded because the buffer was full
+pub fn dropped_updates() -> u64 {
+    STATUS.dropped.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::TryStreamExt;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+    use crate::counter_types::Query;
+
+    /// (connection index, message) for each message the test server received
+    type Received = Arc<Mutex<Vec<(usize, CounterMessage)>>>;
+
+    /// Accepts connections on a background thread, recording every message
+    fn spawn_recording_server(config: &CounterConfig) -> (CounterConfig, Received) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let listener = std::net::TcpListener::bind(config.addr()).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let config = config_for(listener.local_addr().unwrap());
+
+        let server_received = received.clone();
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let listener = TcpListener::from_std(listener).unwrap();
+                    let mut connection_index = 0;
+                    loop {
+                        let (socket, _) = listener.accept().await.unwrap();
+                        let received = server_received.clone();
+                        let index = connection_index;
+                        connection_index += 1;
+                        tokio::spawn(async move {
+                            let mut deserialized = tokio_serde::SymmetricallyFramed::new(
+                                FramedRead::new(socket, LengthDelimitedCodec::new()),
+                                SymmetricalJson::<CounterMessage>::default(),
+                            );
+                            while let Ok(Some(msg)) = deserialized.try_next().await {
+                                received.lock().unwrap().push((index, msg));
+                            }
+                        });
+                    }
+                });
+        });
+
+        (config, received)
+    }
+
+    fn config_for(addr: std::net::SocketAddr) -> CounterConfig {
+        CounterConfig::builder()
+            .host(addr.ip().to_string())
+            .port(addr.port())
+            .build()
+    }
+
+    #[test]
+    fn connection_is_reused_across_sends() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let (config, received) = spawn_recording_server(&CounterConfig::builder().port(0).build());
+        let mut connection = CounterConnection::new(config, &STATUS);
+
+        for i in 0..3 {
+            connection
+                .send(CounterMessage::Query {
+                    id: i,
+                    query: Query::Read {
+                        counter: format!("counter_{}", i).into(),
+                        from_seconds: 0,
+                        to_seconds: u64::MAX,
+                    },
+                })
+                .unwrap();
+        }
+        while received.lock().unwrap().len() < 3 {
+            std::thread::yield_now();
+        }
+
+        let received = received.lock().unwrap();
+        assert!(received.iter().all(|(connection, _)| *connection == 0));
+    }
+
+    fn unused_config() -> CounterConfig {
+        // Bind then drop to find a port with nothing listening
+        config_for(
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap(),
+        )
+    }
+
+    fn key(name: &str) -> Arc<CounterKey> {
+        Arc::new(name.into())
+    }
+
+    fn delta(epoch_seconds: u64, count: u64) -> Vec<CounterState> {
+        vec![CounterState {
+            epoch_seconds,
+            count,
+        }]
+    }
+
+    /// The only test of the global publisher, since shutting it down is final
+    #[test]
+    fn global_publisher_reaches_the_server() {
+        let (config, received) = spawn_recording_server(&CounterConfig::builder().port(0).build());
+        configure(config).unwrap();
+
+        inc_counter("requests").unwrap();
+        set_gauge("depth", 3.0).unwrap();
+        assert_eq!(time_block("latency", || 7), 7);
+        // Each metric is registered with the server as it is first sent
+        let registered = || {
+            received
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(_, msg)| match msg {
+                    CounterMessage::Register { counter, .. } => Some(counter.name.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        while registered() != ["requests", "depth", "latency"] {
+            std::thread::yield_now();
+        }
+        assert!(take_publish_error().is_none());
+
+        shutdown().unwrap();
+        assert_eq!(connection_status(), ConnectionStatus::Connected);
+        assert_eq!((pending_updates(), dropped_updates()), (0, 0));
+        assert!(matches!(inc_counter("requests"), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn counter_handles_share_one_key() {
+        fn requests() -> &'static Counter {
+            crate::counter!("requests")
+        }
+
+        assert!(std::ptr::eq(requests(), requests()));
+        assert!(Arc::ptr_eq(requests().key(), requests().key()));
+        assert_eq!(**requests().key(), CounterKey::new("requests"));
+
+        let labeled = Counter::labeled(CounterKey::new("requests").label("status", "500"));
+        assert_eq!(labeled.key().labels["status"], "500");
+    }
+
+    #[test]
+    fn power_of_two_crossings() {
+        let crossings: Vec<u64> = (1..=16u64)
+            .filter(|count| crosses_power_of_two(count - 1, *count))
+            .collect();
+        assert_eq!(crossings, vec![1, 2, 4, 8, 16]);
+
+        assert!(crosses_power_of_two(0, 1000));
+        assert!(crosses_power_of_two(5, 8));
+        assert!(!crosses_power_of_two(8, 15));
+        assert!(crosses_power_of_two(1000, 1_000_000));
+        assert!(!crosses_power_of_two(u64::MAX - 1, u64::MAX));
+        assert!(!crosses_power_of_two(u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn published_deltas_sum_to_increments() {
+        let mut local = LocalCounterState {
+            current: CounterState {
+                epoch_seconds: 10,
+                count: 0,
+            },
+            published: 0,
+        };
+        let mut sent = Vec::new();
+        for _ in 0..8 {
+            let prev_count = local.current.count;
+            local.current.count += 1;
+            if crosses_power_of_two(prev_count, local.current.count) {
+                sent.extend(local.take_delta());
+            }
+        }
+
+        // Published at 1, 2, 4 and 8 as deltas rather than running totals
+        let counts: Vec<u64> = sent.iter().map(|delta| delta.count).collect();
+        assert_eq!(counts, vec![1, 1, 2, 4]);
+        assert_eq!(counts.iter().sum::<u64>(), 8);
+        assert!(local.take_delta().is_none());
+    }
+
+    #[test]
+    fn send_without_server_is_an_error() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let mut connection = CounterConnection::new(unused_config(), &STATUS);
+
+        assert!(matches!(
+            connection.send(CounterMessage::Query {
+                id: 0,
+                query: Query::Read {
+                    counter: "counter".into(),
+                    from_seconds: 0,
+                    to_seconds: u64::MAX,
+                }
+            }),
+            Err(Error::Transport(_))
+        ));
+    }
+
+    #[test]
+    fn updates_buffer_while_disconnected_and_flush_on_reconnect() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let config = unused_config();
+        let mut connection = CounterConnection::new(config.clone(), &STATUS);
+        assert_eq!(STATUS.get(), ConnectionStatus::Idle);
+
+        connection.publish("a".into(), delta(10, 1)).unwrap();
+        assert!(connection.flush_if_due().is_err());
+        assert_eq!(STATUS.get(), ConnectionStatus::Disconnected);
+        // Backing off, so buffered without another connection attempt
+        connection.publish("a".into(), delta(10, 2)).unwrap();
+        connection.publish("a".into(), delta(10, 3)).unwrap();
+        connection.publish("b".into(), delta(10, 1)).unwrap();
+        assert!(connection.flush_if_due().is_ok());
+        // The failed update is kept apart, and later counts for the same counter and interval are merged
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 3);
+
+        let (_, received) = spawn_recording_server(&config);
+        std::thread::sleep(INITIAL_BACKOFF);
+        connection.publish("a".into(), delta(11, 1)).unwrap();
+        connection.flush_if_due().unwrap();
+        assert_eq!(STATUS.get(), ConnectionStatus::Connected);
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 0);
+
+        // Each counter is registered once, then sent by id
+        while received.lock().unwrap().len() < 4 {
+            std::thread::yield_now();
+        }
+        let received = received.lock().unwrap();
+        let mut registered = HashMap::new();
+        let sent: Vec<(u64, CounterKey, Vec<CounterState>)> = received
+            .iter()
+            .filter_map(|(_, msg)| match msg {
+                CounterMessage::Register { id, counter } => {
+                    registered.insert(*id, counter.clone());
+                    None
+                }
+                CounterMessage::Batch(batch) => {
+                    assert_eq!(batch.source, connection.source);
+                    let entries = batch.counters.iter().map(|entry| {
+                        let MetricRef::Id(id) = entry.counter else {
+                            panic!("expected an id");
+                        };
+                        (batch.seq, registered[&id].clone(), entry.state.clone())
+                    });
+                    Some(entries.collect::<Vec<_>>())
+                }
+                _ => panic!("unexpected message"),
+            })
+            .flatten()
+            .collect();
+        assert_eq!(registered.len(), 2);
+        assert_eq!(
+            sent,
+            vec![
+                (1, "a".into(), delta(10, 1)),
+                (2, "a".into(), [delta(10, 5), delta(11, 1)].concat()),
+                (2, "b".into(), delta(10, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn stalled_server_times_out_and_backs_off() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = CounterConfig {
+            send_timeout_ms: 100,
+            ..config_for(listener.local_addr().unwrap())
+        };
+        // Accepts, then never reads, so writes block once the socket buffers fill
+        std::thread::spawn(move || {
+            let _sockets: Vec<_> = listener.incoming().collect();
+        });
+
+        let mut connection = CounterConnection::new(config, &STATUS);
+        let large_key = "a".repeat(1 << 20);
+        let mut result = Ok(());
+        for i in 0..1_000 {
+            let counter: CounterKey = format!("{}{}", large_key, i).as_str().into();
+            connection.publish(counter, delta(10, 1)).unwrap();
+            let started = Instant::now();
+            result = connection.flush_if_due();
+            assert!(started.elapsed() < Duration::from_secs(2));
+            if result.is_err() {
+                break;
+            }
+        }
+
+        assert!(matches!(result, Err(Error::Transport(e)) if e.kind() == io::ErrorKind::TimedOut));
+        assert_eq!(STATUS.get(), ConnectionStatus::Disconnected);
+        assert!(connection.unsent.is_some());
+        assert!(connection.retry_at.is_some());
+        // The partly written connection is not reused
+        assert!(connection.sink.is_none());
+    }
+
+    #[test]
+    fn updates_lost_with_a_dropped_connection_are_resent() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = config_for(listener.local_addr().unwrap());
+
+        // Closes the first connection after its first update, then records the second
+        let server_received = received.clone();
+        std::thread::spawn(move || {
+            for (index, socket) in listener.incoming().enumerate() {
+                let received = server_received.clone();
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_io()
+                    .build()
+                    .unwrap()
+                    .block_on(async move {
+                        socket.as_ref().unwrap().set_nonblocking(true).unwrap();
+                        let mut deserialized = tokio_serde::SymmetricallyFramed::new(
+                            FramedRead::new(
+                                TcpStream::from_std(socket.unwrap()).unwrap(),
+                                LengthDelimitedCodec::new(),
+                            ),
+                            SymmetricalJson::<CounterMessage>::default(),
+                        );
+                        while let Ok(Some(msg)) = deserialized.try_next().await {
+                            let is_batch = matches!(msg, CounterMessage::Batch(_));
+                            received.lock().unwrap().push((index, msg));
+                            if index == 0 && is_batch {
+                                break;
+                            }
+                        }
+                    });
+            }
+        });
+
+        let mut connection = CounterConnection::new(config, &STATUS);
+        for minute in 10..13 {
+            connection.publish("a".into(), delta(minute, 1)).unwrap();
+            connection.flush_if_due().unwrap();
+            // Lets the server's close, then its reset, arrive before the next write
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(STATUS.get(), ConnectionStatus::Connected);
+
+        // The second update was written into the closed connection, and only arrives resent
+        let batch_seqs = || {
+            received
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(index, msg)| match msg {
+                    CounterMessage::Batch(batch) => Some((*index, batch.seq)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while batch_seqs().len() < 4 && Instant::now() < deadline {
+            std::thread::yield_now();
+        }
+        assert_eq!(batch_seqs(), vec![(0, 1), (1, 1), (1, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn gauges_buffer_alongside_counts() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let mut connection = CounterConnection::new(unused_config(), &STATUS);
+
+        connection.buffer("a".into(), delta(10, 1));
+        connection.buffer("depth".into(), vec![GaugeState::new(10, 4.0)]);
+        connection.buffer("depth".into(), vec![GaugeState::new(10, 2.0)]);
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 2);
+
+        match connection.next_pending() {
+            Some(CounterMessage::Batch(batch)) => {
+                assert_eq!(batch.counters.len(), 1);
+                assert_eq!(batch.gauges[0].counter, "depth".into());
+                let state = &batch.gauges[0].state[0];
+                assert_eq!((state.last, state.max, state.count), (2.0, 4.0, 2));
+            }
+            _ => panic!("expected a batch"),
+        }
+        assert!(connection.next_pending().is_none());
+    }
+
+    #[test]
+    fn histogram_samples_merge_while_buffered() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let mut connection = CounterConnection::new(unused_config(), &STATUS);
+
+        connection.buffer("latency".into(), vec![HistogramState::new(10, 1.0)]);
+        connection.buffer("latency".into(), vec![HistogramState::new(10, 100.0)]);
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 1);
+
+        match connection.next_pending() {
+            Some(CounterMessage::Batch(batch)) => {
+                let state = &batch.histograms[0].state[0];
+                assert_eq!((state.count, state.min, state.max), (2, 1.0, 100.0));
+            }
+            _ => panic!("expected a batch"),
+        }
+    }
+
+    #[test]
+    fn non_finite_gauge_values_are_rejected() {
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(
+                set_gauge("depth", value),
+                Err(Error::InvalidValue(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn non_finite_histogram_samples_are_rejected() {
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(
+                record_histogram("latency", value),
+                Err(Error::InvalidValue(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn updates_handled_together_are_sent_as_one_batch() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let (config, received) = spawn_recording_server(&CounterConfig::builder().port(0).build());
+        let mut state = CounterPublishState {
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            histograms: HashMap::new(),
+            connection: CounterConnection::new(config, &STATUS),
+        };
+
+        for i in 0..50 {
+            state.increment(key(&format!("counter{}", i)), 1).unwrap();
+        }
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 50);
+        state.drained().unwrap();
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 0);
+
+        let batches = || {
+            received
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(_, msg)| match msg {
+                    CounterMessage::Batch(batch) => Some(batch.counters.len()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        while batches().is_empty() {
+            std::thread::yield_now();
+        }
+        assert_eq!(batches(), vec![50]);
+    }
+
+    #[test]
+    fn unpublished_counts_are_sent_on_drop() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let (config, received) = spawn_recording_server(&CounterConfig::builder().port(0).build());
+        let mut state = CounterPublishState {
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            histograms: HashMap::new(),
+            connection: CounterConnection::new(config, &STATUS),
+        };
+
+        // Only the counts at 1, 2 and 4 are published as they happen
+        for _ in 0..5 {
+            state.increment(key("a"), 1).unwrap();
+        }
+        record_sample(&mut state.gauges, &mut state.connection, key("depth"), 3.0).unwrap();
+        record_sample(&mut state.gauges, &mut state.connection, key("depth"), 7.0).unwrap();
+        drop(state);
+
+        let total = || {
+            received
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, msg)| match msg {
+                    CounterMessage::Batch(batch) => batch
+                        .counters
+                        .iter()
+                        .flat_map(|entry| &entry.state)
+                        .map(|state| state.count)
+                        .sum(),
+                    _ => 0,
+                })
+                .sum::<u64>()
+        };
+        let gauge_last = || {
+            received
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .find_map(|(_, msg)| match msg {
+                    CounterMessage::Batch(batch) => {
+                        batch.gauges.first().map(|entry| entry.state[0].last)
+                    }
+                    _ => None,
+                })
+        };
+        while total() < 5 || gauge_last() != Some(7.0) {
+            std::thread::yield_now();
+        }
+        assert_eq!(total(), 5);
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn counts_are_stamped_with_their_report_interval() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let config = CounterConfig {
+            report_interval_seconds: 10,
+            ..unused_config()
+        };
+        let mut state = CounterPublishState {
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            histograms: HashMap::new(),
+            connection: CounterConnection::new(config, &STATUS),
+        };
+
+        let before = get_epoc_seconds();
+        // The first count is published, and kept since nothing is listening
+        state.increment(key("a"), 1).unwrap();
+        assert!(state.drained().is_err());
+        let after = get_epoc_seconds();
+
+        match &state.connection.unsent {
+            Some(CounterMessage::Batch(batch)) => {
+                let start = batch.counters[0].state[0].epoch_seconds;
+                assert_eq!(start % 10, 0);
+                assert!((before - before % 10..=after).contains(&start));
+            }
+            _ => panic!("expected the count's update"),
+        }
+
+        // Don't wait out the shutdown flush with no server
+        std::mem::forget(state);
+    }
+
+    #[test]
+    fn tick_sends_the_rest_of_closed_intervals() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let now = current_interval(&unused_config());
+        let previous = now - unused_config().report_interval_seconds;
+        let local = |epoch_seconds, count, published| LocalCounterState {
+            current: CounterState {
+                epoch_seconds,
+                count,
+            },
+            published,
+        };
+        let mut state = CounterPublishState {
+            counters: HashMap::from([
+                // 5 increments, published at 1, 2 and 4
+                (key("closed"), local(previous, 5, 4)),
+                (key("open"), local(now, 5, 4)),
+            ]),
+            gauges: HashMap::new(),
+            histograms: HashMap::new(),
+            connection: CounterConnection::new(unused_config(), &STATUS),
+        };
+
+        assert!(state.tick().is_err());
+
+        // Kept for the next attempt, since nothing is listening
+        match &state.connection.unsent {
+            Some(CounterMessage::Batch(batch)) => {
+                assert_eq!(batch.counters.len(), 1);
+                assert_eq!(batch.counters[0].counter, "closed".into());
+                assert_eq!(batch.counters[0].state, delta(previous, 1));
+            }
+            _ => panic!("expected the closed interval's update"),
+        }
+        assert_eq!(state.connection.pending_len(), 0);
+        assert_eq!(state.counters[&CounterKey::from("open")].published, 4);
+        // Nothing is sent twice
+        let closed = &state.counters[&CounterKey::from("closed")];
+        assert_eq!(closed.published, closed.current.count);
+
+        // Don't wait out the shutdown flush with no server
+        std::mem::forget(state);
+    }
+
+    #[test]
+    fn flush_gives_up_at_the_deadline() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let mut connection = CounterConnection::new(unused_config(), &STATUS);
+        connection.buffer("a".into(), delta(10, 1));
+
+        let start = Instant::now();
+        assert!(connection
+            .flush_until(start + Duration::from_millis(300))
+            .is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn batches_are_bounded() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let mut connection = CounterConnection::new(unused_config(), &STATUS);
+
+        for i in 0..(MAX_BATCH_STATES + 5) {
+            connection.buffer(format!("counter_{}", i).into(), delta(10, 1));
+        }
+
+        let mut sizes = Vec::new();
+        while let Some(CounterMessage::Batch(batch)) = connection.next_pending() {
+            sizes.push((batch.seq, batch.len()));
+        }
+        assert_eq!(sizes, vec![(1, MAX_BATCH_STATES), (2, 5)]);
+    }
+
+    #[test]
+    fn pending_buffer_is_bounded() {
+        static STATUS: PublisherStatus = PublisherStatus::new();
+        let mut connection = CounterConnection::new(unused_config(), &STATUS);
+
+        for minute in 0..(MAX_PENDING_UPDATES as u64 + 5) {
+            connection.buffer("a".into(), delta(minute, 1));
+        }
+
+        assert_eq!(STATUS.pending.load(Ordering::Relaxed), MAX_PENDING_UPDATES);
+        assert_eq!(STATUS.dropped.load(Ordering::Relaxed), 5);
+        // The oldest intervals are the ones dropped
+        assert_eq!(
+            connection.counts.states[&"a".into()].keys().next(),
+            Some(&5)
+        );
+    }
 }
@@ -0,0 +1,464 @@
+// Query the counter server for what it holds, over the same framing the publisher uses
+
+use std::io;
+use std::ops::Range;
+
+use futures::{SinkExt, TryStreamExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio_serde::formats::SymmetricalJson;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+use crate::counter::connect_stream;
+use crate::counter_config::CounterConfig;
+use crate::counter_types::{
+    CounterKey, CounterMessage, CounterResponse, CounterState, GaugeState, HistogramState, Labels,
+    MetricList, Query, QueryResult, Series,
+};
+use crate::error::Error;
+
+type Requests = tokio_serde::SymmetricallyFramed<
+    FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
+    CounterMessage,
+    SymmetricalJson<CounterMessage>,
+>;
+type Responses = tokio_serde::SymmetricallyFramed<
+    FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    CounterResponse,
+    SymmetricalJson<CounterResponse>,
+>;
+
+/// A connection to the counter server for reading series back.
+/// Ranges are in epoch seconds, and the server answers with the intervals starting within them.
+pub struct CounterClient {
+    requests: Requests,
+    responses: Responses,
+    next_id: u64,
+}
+
+impl CounterClient {
+    pub async fn connect(config: &CounterConfig) -> Result<CounterClient, Error> {
+        let (reader, writer) = connect_stream(config).await?.into_split();
+        Ok(CounterClient {
+            requests: tokio_serde::SymmetricallyFramed::new(
+                FramedWrite::new(writer, LengthDelimitedCodec::new()),
+                SymmetricalJson::<CounterMessage>::default(),
+            ),
+            responses: tokio_serde::SymmetricallyFramed::new(
+                FramedRead::new(reader, LengthDelimitedCodec::new()),
+                SymmetricalJson::<CounterResponse>::default(),
+            ),
+            next_id: 1,
+        })
+    }
+
+    /// A single counter series, by its exact label set
+    pub async fn read(
+        &mut self,
+        counter: impl Into<CounterKey>,
+        range: Range<u64>,
+    ) -> Result<Series<CounterState>, Error> {
+        let query = Query::Read {
+            counter: counter.into(),
+            from_seconds: range.start,
+            to_seconds: range.end,
+        };
+        match self.query(query).await? {
+            QueryResult::Counters(series) => Ok(series),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// The sum of every counter with the name and at least these labels
+    pub async fn aggregate(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+    ) -> Result<Series<CounterState>, Error> {
+        let query = Query::Aggregate {
+            name: name.into(),
+            labels,
+            from_seconds: range.start,
+            to_seconds: range.end,
+        };
+        match self.query(query).await? {
+            QueryResult::Counters(series) => Ok(series),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn read_gauge(
+        &mut self,
+        gauge: impl Into<CounterKey>,
+        range: Range<u64>,
+    ) -> Result<Series<GaugeState>, Error> {
+        let query = Query::ReadGauge {
+            gauge: gauge.into(),
+            from_seconds: range.start,
+            to_seconds: range.end,
+        };
+        match self.query(query).await? {
+            QueryResult::Gauges(series) => Ok(series),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn aggregate_gauges(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+    ) -> Result<Series<GaugeState>, Error> {
+        let query = Query::AggregateGauges {
+            name: name.into(),
+            labels,
+            from_seconds: range.start,
+            to_seconds: range.end,
+        };
+        match self.query(query).await? {
+            QueryResult::Gauges(series) => Ok(series),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn read_histogram(
+        &mut self,
+        histogram: impl Into<CounterKey>,
+        range: Range<u64>,
+    ) -> Result<Series<HistogramState>, Error> {
+        let query = Query::ReadHistogram {
+            histogram: histogram.into(),
+            from_seconds: range.start,
+            to_seconds: range.end,
+        };
+        match self.query(query).await? {
+            QueryResult::Histograms(series) => Ok(series),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn aggregate_histograms(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+    ) -> Result<Series<HistogramState>, Error> {
+        let query = Query::AggregateHistograms {
+            name: name.into(),
+            labels,
+            from_seconds: range.start,
+            to_seconds: range.end,
+        };
+        match self.query(query).await? {
+            QueryResult::Histograms(series) => Ok(series),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Quantiles, ie. 0.5 and 0.99, of the matching histograms merged over the range.
+    /// None for each quantile if nothing was recorded.
+    pub async fn percentiles(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+        quantiles: &[f64],
+    ) -> Result<Vec<Option<f64>>, Error> {
+        let query = Query::Percentiles {
+            name: name.into(),
+            labels,
+            from_seconds: range.start,
+            to_seconds: range.end,
+            quantiles: quantiles.to_vec(),
+        };
+        match self.query(query).await? {
+            QueryResult::Percentiles(values) => Ok(values),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// The key of every series the server holds
+    pub async fn list(&mut self) -> Result<MetricList, Error> {
+        match self.query(Query::List).await? {
+            QueryResult::Metrics(metrics) => Ok(metrics),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn query(&mut self, query: Query) -> Result<QueryResult, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests
+            .send(CounterMessage::Query { id, query })
+            .await?;
+
+        loop {
+            let response = self.responses.try_next().await?.ok_or(Error::Closed)?;
+            // Skip answers to earlier queries whose futures were dropped before they arrived
+            if response.id != id {
+                continue;
+            }
+            return match response.result {
+                QueryResult::Error(e) => Err(Error::Query(e)),
+                result => Ok(result),
+            };
+        }
+    }
+}
+
+fn unexpected(result: QueryResult) -> Error {
+    Error::Transport(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response {:?}", result),
+    ))
+}
+
+/// `CounterClient` for code without a tokio runtime, driving the connection on its own
+pub struct BlockingCounterClient {
+    runtime: tokio::runtime::Runtime,
+    client: CounterClient,
+}
+
+impl BlockingCounterClient {
+    pub fn connect(config: &CounterConfig) -> Result<BlockingCounterClient, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()?;
+        let client = runtime.block_on(CounterClient::connect(config))?;
+        Ok(BlockingCounterClient { runtime, client })
+    }
+
+    pub fn read(
+        &mut self,
+        counter: impl Into<CounterKey>,
+        range: Range<u64>,
+    ) -> Result<Series<CounterState>, Error> {
+        self.runtime.block_on(self.client.read(counter, range))
+    }
+
+    pub fn aggregate(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+    ) -> Result<Series<CounterState>, Error> {
+        self.runtime
+            .block_on(self.client.aggregate(name, labels, range))
+    }
+
+    pub fn read_gauge(
+        &mut self,
+        gauge: impl Into<CounterKey>,
+        range: Range<u64>,
+    ) -> Result<Series<GaugeState>, Error> {
+        self.runtime.block_on(self.client.read_gauge(gauge, range))
+    }
+
+    pub fn aggregate_gauges(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+    ) -> Result<Series<GaugeState>, Error> {
+        self.runtime
+            .block_on(self.client.aggregate_gauges(name, labels, range))
+    }
+
+    pub fn read_histogram(
+        &mut self,
+        histogram: impl Into<CounterKey>,
+        range: Range<u64>,
+    ) -> Result<Series<HistogramState>, Error> {
+        self.runtime
+            .block_on(self.client.read_histogram(histogram, range))
+    }
+
+    pub fn aggregate_histograms(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+    ) -> Result<Series<HistogramState>, Error> {
+        self.runtime
+            .block_on(self.client.aggregate_histograms(name, labels, range))
+    }
+
+    pub fn percentiles(
+        &mut self,
+        name: impl Into<String>,
+        labels: Labels,
+        range: Range<u64>,
+        quantiles: &[f64],
+    ) -> Result<Vec<Option<f64>>, Error> {
+        self.runtime
+            .block_on(self.client.percentiles(name, labels, range, quantiles))
+    }
+
+    pub fn list(&mut self) -> Result<MetricList, Error> {
+        self.runtime.block_on(self.client.list())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::counter_types::{QueryError, Resolution};
+
+    /// Answers each query from `answer`, first sending a response for a query nobody asked
+    fn spawn_answering_server(answer: fn(Query) -> QueryResult) -> CounterConfig {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let listener = TcpListener::from_std(listener).unwrap();
+                    let (socket, _) = listener.accept().await.unwrap();
+                    let (reader, writer) = socket.into_split();
+                    let mut requests = tokio_serde::SymmetricallyFramed::new(
+                        FramedRead::new(reader, LengthDelimitedCodec::new()),
+                        SymmetricalJson::<CounterMessage>::default(),
+                    );
+                    let mut responses = tokio_serde::SymmetricallyFramed::new(
+                        FramedWrite::new(writer, LengthDelimitedCodec::new()),
+                        SymmetricalJson::<CounterResponse>::default(),
+                    );
+                    while let Ok(Some(CounterMessage::Query { id, query })) =
+                        requests.try_next().await
+                    {
+                        let stale = CounterResponse {
+                            id: 0,
+                            result: QueryResult::Percentiles(vec![]),
+                        };
+                        let response = CounterResponse {
+                            id,
+                            result: answer(query),
+                        };
+                        responses.send(stale).await.unwrap();
+                        responses.send(response).await.unwrap();
+                    }
+                });
+        });
+
+        CounterConfig::builder()
+            .host(addr.ip().to_string())
+            .port(addr.port())
+            .build()
+    }
+
+    #[test]
+    fn queries_return_typed_results() {
+        let config = spawn_answering_server(|query| match query {
+            // Like the server, only the intervals starting in the range
+            Query::Read {
+                counter,
+                from_seconds,
+                to_seconds,
+            } if counter == "a".into() => QueryResult::Counters(Series {
+                states: [5, 10, 15]
+                    .into_iter()
+                    .filter(|epoch_seconds| (from_seconds..to_seconds).contains(epoch_seconds))
+                    .map(|epoch_seconds| CounterState {
+                        epoch_seconds,
+                        count: 1,
+                    })
+                    .collect(),
+                resolutions: vec![Resolution {
+                    from_seconds: 0,
+                    interval_seconds: 1,
+                }],
+            }),
+            Query::Read { counter, .. } => QueryResult::Error(QueryError::UnknownMetric(counter)),
+            Query::List => QueryResult::Metrics(MetricList {
+                counters: vec!["a".into()],
+                ..MetricList::default()
+            }),
+            _ => QueryResult::Percentiles(vec![None]),
+        });
+        let mut client = BlockingCounterClient::connect(&config).unwrap();
+
+        let series = client.read("a", 10..20).unwrap();
+        let starts: Vec<u64> = series
+            .states
+            .iter()
+            .map(|state| state.epoch_seconds)
+            .collect();
+        assert_eq!(starts, vec![10, 15]);
+        assert_eq!(series.resolutions.len(), 1);
+
+        assert!(matches!(
+            client.read("b", 0..20),
+            Err(Error::Query(QueryError::UnknownMetric(_)))
+        ));
+        assert_eq!(client.list().unwrap().counters, vec!["a".into()]);
+        // A result of the wrong kind is an error rather than a panic
+        assert!(matches!(
+            client.read_gauge("a", 0..20),
+            Err(Error::Transport(_))
+        ));
+    }
+
+    #[test]
+    fn aggregates_histograms_and_percentiles() {
+        fn series<S>(state: S) -> Series<S> {
+            Series {
+                states: vec![state],
+                resolutions: vec![],
+            }
+        }
+        let config = spawn_answering_server(|query| match query {
+            Query::Aggregate {
+                from_seconds,
+                to_seconds,
+                ..
+            } => QueryResult::Counters(Series {
+                states: (from_seconds..to_seconds)
+                    .contains(&10)
+                    .then_some(CounterState {
+                        epoch_seconds: 10,
+                        count: 3,
+                    })
+                    .into_iter()
+                    .collect(),
+                resolutions: vec![],
+            }),
+            Query::AggregateGauges { .. } => QueryResult::Gauges(series(GaugeState::new(10, 2.0))),
+            Query::ReadHistogram { .. } | Query::AggregateHistograms { .. } => {
+                QueryResult::Histograms(series(HistogramState::new(10, 4.0)))
+            }
+            Query::Percentiles { quantiles, .. } => {
+                QueryResult::Percentiles(quantiles.iter().map(|q| Some(q * 100.0)).collect())
+            }
+            _ => QueryResult::Error(QueryError::InvalidQuery("unexpected".to_string())),
+        });
+        let mut client = BlockingCounterClient::connect(&config).unwrap();
+        let labels = Labels::new();
+
+        let counts = client.aggregate("a", labels.clone(), 0..20).unwrap();
+        assert_eq!(counts.states[0].count, 3);
+        // Outside the range
+        assert!(client
+            .aggregate("a", labels.clone(), 11..20)
+            .unwrap()
+            .states
+            .is_empty());
+        let gauges = client.aggregate_gauges("a", labels.clone(), 0..20).unwrap();
+        assert_eq!(gauges.states[0].last, 2.0);
+        let histogram = client.read_histogram("a", 0..20).unwrap();
+        assert_eq!(histogram.states[0].max, 4.0);
+        let histograms = client
+            .aggregate_histograms("a", labels.clone(), 0..20)
+            .unwrap();
+        assert_eq!(histograms.states[0].count, 1);
+        assert_eq!(
+            client.percentiles("a", labels, 0..20, &[0.5]).unwrap(),
+            vec![Some(50.0)]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+// Where the counter server listens and the counter client connects
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 7878;
+pub const DEFAULT_REPORT_INTERVAL_SECONDS: u64 = 60;
+pub const DEFAULT_SEND_TIMEOUT_MS: u64 = 5_000;
+
+/// Path of a JSON config file, ie. `{"host": "metrics.internal", "port": 7878}`
+pub const CONFIG_FILE_ENV: &str = "ASYNC_PUB_COUNTER_CONFIG";
+pub const HOST_ENV: &str = "ASYNC_PUB_COUNTER_HOST";
+pub const PORT_ENV: &str = "ASYNC_PUB_COUNTER_PORT";
+pub const REPORT_INTERVAL_ENV: &str = "ASYNC_PUB_COUNTER_REPORT_INTERVAL";
+pub const SEND_TIMEOUT_ENV: &str = "ASYNC_PUB_COUNTER_SEND_TIMEOUT_MS";
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CounterConfig {
+    /// Hostname, IPv4 or IPv6 address
+    pub host: String,
+    /// 0 lets the server pick a free port
+    pub port: u16,
+    /// Clients send what they record in intervals of this many seconds,
+    /// the finest resolution the server can keep their metrics at
+    pub report_interval_seconds: u64,
+    /// How long a client waits to connect and write an update before
+    /// counting the attempt as failed and backing off
+    pub send_timeout_ms: u64,
+}
+
+impl Default for CounterConfig {
+    fn default() -> Self {
+        CounterConfig {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            report_interval_seconds: DEFAULT_REPORT_INTERVAL_SECONDS,
+            send_timeout_ms: DEFAULT_SEND_TIMEOUT_MS,
+        }
+    }
+}
+
+impl CounterConfig {
+    pub fn builder() -> CounterConfigBuilder {
+        CounterConfigBuilder::default()
+    }
+
+    /// Defaults, overridden by the config file named in `ASYNC_PUB_COUNTER_CONFIG`,
+    /// overridden by `ASYNC_PUB_COUNTER_HOST`, `ASYNC_PUB_COUNTER_PORT`,
+    /// `ASYNC_PUB_COUNTER_REPORT_INTERVAL` and `ASYNC_PUB_COUNTER_SEND_TIMEOUT_MS`
+    pub fn from_env() -> Result<CounterConfig, Error> {
+        Ok(CounterConfig::builder().env()?.build())
+    }
+
+    /// `host:port`, with IPv6 addresses in brackets
+    pub fn addr(&self) -> String {
+        if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    pub fn send_timeout(&self) -> Duration {
+        Duration::from_millis(self.send_timeout_ms)
+    }
+
+    /// Every socket address the host resolves to
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, Error> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(self.addr()).await?.collect();
+        if addrs.is_empty() {
+            return Err(Error::Config(format!(
+                "{} resolved to no addresses",
+                self.addr()
+            )));
+        }
+        Ok(addrs)
+    }
+}
+
+/// Later settings override earlier ones, so apply sources from least to most specific
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CounterConfigBuilder {
+    host: Option<String>,
+    port: Option<u16>,
+    report_interval_seconds: Option<u64>,
+    send_timeout_ms: Option<u64>,
+}
+
+impl CounterConfigBuilder {
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        let host: String = host.into();
+        // Accept bracketed IPv6 as well as the bare address
+        self.host = Some(
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+        );
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// A minute by default. Set it as fine as the server's finest retention tier,
+    /// ie. 10 to keep 10 second resolution. 0 is treated as 1.
+    pub fn report_interval_seconds(mut self, seconds: u64) -> Self {
+        self.report_interval_seconds = Some(seconds);
+        self
+    }
+
+    /// 5 seconds by default. 0 is treated as 1.
+    pub fn send_timeout_ms(mut self, ms: u64) -> Self {
+        self.send_timeout_ms = Some(ms);
+        self
+    }
+
+    /// Apply settings from a JSON file
+    pub fn file(self, path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        let file: CounterConfigBuilder = serde_json::from_str(&contents)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        Ok(self.merge(file))
+    }
+
+    /// Apply the config file and variables from the environment
+    pub fn env(self) -> Result<Self, Error> {
+        self.env_with(|key| std::env::var(key).ok())
+    }
+
+    fn env_with<F>(mut self, var: F) -> Result<Self, Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(path) = var(CONFIG_FILE_ENV) {
+            self = self.file(Path::new(&path))?;
+        }
+        if let Some(host) = var(HOST_ENV) {
+            self = self.host(host);
+        }
+        if let Some(port) = var(PORT_ENV) {
+            let port = port
+                .parse()
+                .map_err(|_| Error::Config(format!("{} is not a port: {}", PORT_ENV, port)))?;
+            self = self.port(port);
+        }
+        if let Some(seconds) = var(REPORT_INTERVAL_ENV) {
+            let seconds = seconds.parse().map_err(|_| {
+                Error::Config(format!(
+                    "{} is not a number of seconds: {}",
+                    REPORT_INTERVAL_ENV, seconds
+                ))
+            })?;
+            self = self.report_interval_seconds(seconds);
+        }
+        if let Some(ms) = var(SEND_TIMEOUT_ENV) {
+            let ms = ms.parse().map_err(|_| {
+                Error::Config(format!(
+                    "{} is not a number of milliseconds: {}",
+                    SEND_TIMEOUT_ENV, ms
+                ))
+            })?;
+            self = self.send_timeout_ms(ms);
+        }
+        Ok(self)
+    }
+
+    fn merge(self, other: CounterConfigBuilder) -> Self {
+        let mut merged = self;
+        if let Some(host) = other.host {
+            merged = merged.host(host);
+        }
+        if let Some(port) = other.port {
+            merged = merged.port(port);
+        }
+        if let Some(seconds) = other.report_interval_seconds {
+            merged = merged.report_interval_seconds(seconds);
+        }
+        if let Some(ms) = other.send_timeout_ms {
+            merged = merged.send_timeout_ms(ms);
+        }
+        merged
+    }
+
+    pub fn build(self) -> CounterConfig {
+        CounterConfig {
+            host: self.host.unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port: self.port.unwrap_or(DEFAULT_PORT),
+            report_interval_seconds: self
+                .report_interval_seconds
+                .unwrap_or(DEFAULT_REPORT_INTERVAL_SECONDS)
+                .max(1),
+            send_timeout_ms: self
+                .send_timeout_ms
+                .unwrap_or(DEFAULT_SEND_TIMEOUT_MS)
+                .max(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        assert_eq!(CounterConfig::builder().build().addr(), "127.0.0.1:7878");
+    }
+
+    #[test]
+    fn ipv6_addr_is_bracketed() {
+        let config = CounterConfig::builder().host("::1").port(9000).build();
+        assert_eq!(config.host, "::1");
+        assert_eq!(config.addr(), "[::1]:9000");
+
+        let config = CounterConfig::builder().host("[::1]").build();
+        assert_eq!(config.addr(), "[::1]:7878");
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let path =
+            std::env::temp_dir().join(format!("async-pub-config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"host": "metrics.internal", "port": 9000, "report_interval_seconds": 30}"#,
+        )
+        .unwrap();
+
+        let vars = HashMap::from([
+            (CONFIG_FILE_ENV, path.to_str().unwrap().to_string()),
+            (PORT_ENV, "9100".to_string()),
+            (REPORT_INTERVAL_ENV, "10".to_string()),
+            (SEND_TIMEOUT_ENV, "250".to_string()),
+        ]);
+        let config = CounterConfig::builder()
+            .port(1)
+            .env_with(|key| vars.get(key).cloned())
+            .unwrap()
+            .build();
+
+        assert_eq!(config.host, "metrics.internal");
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.report_interval_seconds, 10);
+        assert_eq!(config.send_timeout(), Duration::from_millis(250));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bad_port_is_rejected() {
+        let result = CounterConfig::builder()
+            .env_with(|key| (key == PORT_ENV).then(|| "not a port".to_string()));
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn resolves_hostnames() {
+        let addrs = CounterConfig::builder()
+            .host("localhost")
+            .port(7878)
+            .build()
+            .resolve()
+            .await
+            .unwrap();
+        assert!(addrs
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 7878));
+    }
+}
@@ -0,0 +1,208 @@
+// How long the counter server keeps each series, and at what resolution.
+//
+// A series is a list of tiers, finest first. States start in the first tier and graduate into
+// each coarser tier as they age past the one they're in, merging into its longer intervals.
+// Clients report per interval, a minute by default, so a tier finer than theirs holds one state
+// per client interval.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// `interval_count` intervals of `interval_seconds` each
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetentionTier {
+    pub interval_seconds: u64,
+    pub interval_count: usize,
+}
+
+impl RetentionTier {
+    /// Seconds of data the tier holds, None if that overflows
+    pub fn span_seconds(&self) -> Option<u64> {
+        self.interval_seconds
+            .checked_mul(self.interval_count as u64)
+    }
+}
+
+/// Validated retention tiers, finest first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Retention {
+    tiers: Vec<RetentionTier>,
+}
+
+impl Retention {
+    /// Each tier's interval must divide the next one's, so a state's interval
+    /// lines up with exactly one interval of every coarser tier
+    pub fn new(tiers: Vec<RetentionTier>) -> Result<Retention, Error> {
+        if tiers.is_empty() {
+            return Err(Error::Config(
+                "retention needs at least one tier".to_string(),
+            ));
+        }
+        if let Some(tier) = tiers.iter().find(|tier| tier.interval_seconds == 0) {
+            return Err(Error::Config(format!(
+                "retention tier {:?} has an interval of 0 seconds, it must be nonzero",
+                tier
+            )));
+        }
+        if let Some(tier) = tiers.iter().find(|tier| tier.interval_count == 0) {
+            return Err(Error::Config(format!(
+                "retention tier {:?} holds no intervals",
+                tier
+            )));
+        }
+        if let Some(pair) = tiers
+            .windows(2)
+            .find(|pair| pair[1].interval_seconds % pair[0].interval_seconds != 0)
+        {
+            return Err(Error::Config(format!(
+                "retention interval of {} seconds doesn't divide the next tier's {}",
+                pair[0].interval_seconds, pair[1].interval_seconds
+            )));
+        }
+        // Tiers hold data through the sum of their spans, which must fit in epoch seconds
+        let total = tiers.iter().try_fold(0u64, |total, tier| {
+            tier.span_seconds().and_then(|span| total.checked_add(span))
+        });
+        if total.is_none() {
+            return Err(Error::Config(
+                "retention tiers span more seconds than fit in a u64".to_string(),
+            ));
+        }
+        Ok(Retention { tiers })
+    }
+
+    /// Tiers from JSON, ie. `[{"interval_seconds": 10, "interval_count": 360}]`
+    pub fn from_json(json: &str) -> Result<Retention, Error> {
+        let tiers = serde_json::from_str(json)
+            .map_err(|e| Error::Config(format!("invalid retention tiers: {}", e)))?;
+        Retention::new(tiers)
+    }
+
+    pub fn tiers(&self) -> &[RetentionTier] {
+        &self.tiers
+    }
+
+    /// Each tier with the age its data is kept until, the sum of its span and the finer tiers'
+    pub fn cutoffs(&self) -> impl Iterator<Item = (&RetentionTier, u64)> {
+        // `new` checked that the sum fits
+        self.tiers.iter().scan(0, |cutoff, tier| {
+            *cutoff += tier.interval_seconds * tier.interval_count as u64;
+            Some((tier, *cutoff))
+        })
+    }
+}
+
+/// 1 minute resolution for 6 hours, stepping down to 3 hours through 90 days
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            tiers: vec![
+                // 6 hours of 1 minute resolution
+                // Through 6 hours
+                RetentionTier {
+                    interval_seconds: 60,
+                    interval_count: 6 * 60,
+                },
+                // 18 hours of 5 minute resolution
+                // Through 24 hours
+                RetentionTier {
+                    interval_seconds: 5 * 60,
+                    interval_count: 18 * (60 / 5),
+                },
+                // 5 days of 15 minute resolution
+                // Through 7 days
+                RetentionTier {
+                    interval_seconds: 15 * 60,
+                    interval_count: 5 * 24 * (60 / 15),
+                },
+                // 23 days of 1 hour resolution
+                // Through 30 days
+                RetentionTier {
+                    interval_seconds: 60 * 60,
+                    interval_count: 23 * 24,
+                },
+                // 60 days of 3 hour resolution
+                // Through 90 days
+                RetentionTier {
+                    interval_seconds: 3 * 60 * 60,
+                    interval_count: 60 * (24 / 3),
+                },
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(interval_seconds: u64, interval_count: usize) -> RetentionTier {
+        RetentionTier {
+            interval_seconds,
+            interval_count,
+        }
+    }
+
+    fn config_error(result: Result<Retention, Error>) -> String {
+        match result {
+            Err(Error::Config(reason)) => reason,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tiers_are_validated() {
+        assert!(Retention::new(vec![tier(60, 60), tier(600, 6), tier(3600, 24 * 365)]).is_ok());
+        assert!(Retention::new(Retention::default().tiers().to_vec()).is_ok());
+
+        assert!(matches!(Retention::new(vec![]), Err(Error::Config(_))));
+        assert!(matches!(
+            Retention::new(vec![tier(60, 0)]),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            Retention::new(vec![tier(600, 6), tier(900, 4)]),
+            Err(Error::Config(_))
+        ));
+
+        let retention =
+            Retention::from_json(r#"[{"interval_seconds": 60, "interval_count": 1440}]"#).unwrap();
+        assert_eq!(retention.tiers(), &[tier(60, 1440)]);
+        assert!(matches!(
+            Retention::from_json(r#"[{"interval_seconds": 60}]"#),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            Retention::from_json(r#"[{"interval_seconds": 1.5, "interval_count": 60}]"#),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn ten_second_tiers_are_kept() {
+        let retention = Retention::from_json(
+            r#"[{"interval_seconds": 10, "interval_count": 360},
+                {"interval_seconds": 60, "interval_count": 1440}]"#,
+        )
+        .unwrap();
+        let cutoffs: Vec<u64> = retention.cutoffs().map(|(_, cutoff)| cutoff).collect();
+        assert_eq!(cutoffs, vec![3600, 3600 + 24 * 3600]);
+    }
+
+    #[test]
+    fn zero_intervals_are_rejected() {
+        let reason = config_error(Retention::new(vec![tier(60, 60), tier(0, 6)]));
+        assert!(reason.contains("must be nonzero"), "{}", reason);
+    }
+
+    #[test]
+    fn overflowing_spans_are_rejected() {
+        let reason = config_error(Retention::new(vec![tier(u64::MAX / 2, 3)]));
+        assert!(reason.contains("fit in a u64"), "{}", reason);
+        // Each span fits, but not their sum
+        let halves = vec![tier(1, usize::MAX / 2 + 1), tier(1, usize::MAX / 2 + 1)];
+        let reason = config_error(Retention::new(halves));
+        assert!(reason.contains("fit in a u64"), "{}", reason);
+    }
+}
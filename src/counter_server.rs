@@ -1,281 +1,1332 @@
-use core::{panic, time};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    ops::Range,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
     sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 use futures::prelude::*;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+use tokio::task::JoinSet;
 use tokio_serde::formats::*;
-use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
-
-use crate::counter_types::{get_epoc_minutes, CounterMessage, CounterState, CounterUpdateMessage};
-
-struct TimeBucketSpec {
-    interval_minutes: u64,
-    interval_count: usize,
-}
-
-const TIME_BUCKET_COUNT: usize = 5;
-/// Newest to oldest
-type TimeSeries = [TimeBucket; TIME_BUCKET_COUNT];
-const TIME_BUCKET_SPECS: [TimeBucketSpec; TIME_BUCKET_COUNT] = [
-    // 6 hours of 1 minute resolution
-    // Through 6 hours
-    TimeBucketSpec {
-        interval_minutes: 1,
-        interval_count: 6 * 60,
-    },
-    // 18 hours of 5 minute resolution
-    // Through 24 hours
-    TimeBucketSpec {
-        interval_minutes: 5,
-        interval_count: 18 * (60 / 5),
-    },
-    // 5 days of 15 minute resolution
-    // Through 7 days
-    TimeBucketSpec {
-        interval_minutes: 15,
-        interval_count: 5 * 24 * (60 / 15),
-    },
-    // 23 days of 1 hour resolution
-    // Through 30 days
-    TimeBucketSpec {
-        interval_minutes: 60,
-        interval_count: 23 * 24,
-    },
-    // 60 days of 3 hour resolution
-    // Through 90 days
-    TimeBucketSpec {
-        interval_minutes: 3 * 60,
-        interval_count: 60 * (24 / 3),
-    },
-];
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+
+use crate::counter_config::CounterConfig;
+use crate::counter_retention::Retention;
+use crate::counter_snapshot::{self, SeriesSnapshot, Snapshot, SNAPSHOT_VERSION};
+use crate::counter_types::{
+    get_epoc_seconds, BatchEntry, BatchMessage, CounterKey, CounterMessage, CounterResponse,
+    CounterState, GaugeState, HistogramState, IntervalState, Labels, MetricList, MetricRef, Query,
+    QueryError, QueryResult, Resolution, Series, UpdateMessage,
+};
+use crate::counter_wal::{FsyncPolicy, Wal};
+use crate::error::Error;
+
+/// Newest to oldest, one bucket per retention tier
+type TimeSeries<S> = Vec<TimeBucket<S>>;
+
+/// States further ahead of the server clock than this are rejected
+const MAX_CLOCK_SKEW_SECONDS: u64 = 5 * 60;
+/// Sources that send nothing for this long are forgotten by the next sweep, so exited clients
+/// don't pile up.
+/// A resend from a forgotten source would be applied again, so this is far longer than
+/// any reconnect a client retries through.
+const SOURCE_IDLE_SECONDS: u64 = 24 * 60 * 60;
+/// Wait before accepting again after an error, ie. running out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Matches how long a client waits to flush its counts when shutting down
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_WAL_FSYNC: FsyncPolicy = FsyncPolicy::Interval(Duration::from_secs(1));
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-struct TimeBucket {
-    /// Minutes per interval
-    interval_minutes: u64,
-    /// This bucket holds [previous cutoff_minutes, cutoff_minutes) minutes of data
-    cutoff_minutes: u64,
-    /// Oldest to newest
-    data: VecDeque<CounterState>,
+struct TimeBucket<S> {
+    /// Seconds per interval
+    interval_seconds: u64,
+    /// This bucket holds data aged [previous cutoff_seconds, cutoff_seconds)
+    cutoff_seconds: u64,
+    /// Newest to oldest
+    data: VecDeque<S>,
 }
 
+/// Runs until SIGINT or SIGTERM, then drains connections and returns.
+/// Persists to the snapshot file named by `ASYNC_PUB_COUNTER_SNAPSHOT`, if set,
+/// logging updates between snapshots to the directory named by `ASYNC_PUB_COUNTER_WAL`.
+/// Keeps the retention tiers in `ASYNC_PUB_COUNTER_RETENTION`, as JSON, if set.
 #[tokio::main]
-pub async fn run_server() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:7878").await?;
+pub async fn run_server(config: CounterConfig) -> Result<(), Error> {
+    let mut builder = CounterServer::builder()
+        .config(config)
+        .shutdown_on_signals(true);
+    if let Some(path) = std::env::var_os(SNAPSHOT_PATH_ENV) {
+        builder = builder.snapshot_path(path);
+    }
+    if let Some(dir) = std::env::var_os(WAL_DIR_ENV) {
+        builder = builder.wal_dir(dir);
+    }
+    if let Ok(tiers) = std::env::var(RETENTION_ENV) {
+        builder = builder.retention(Retention::from_json(&tiers)?);
+    }
+    builder.bind().await?.serve().await
+}
 
-    let counters: HashMap<String, Arc<Mutex<[TimeBucket; 5]>>> = HashMap::new();
-    let counters = Arc::new(RwLock::new(counters));
-    // let time_series = Arc::new(Mutex::new(time_series));
+pub const SNAPSHOT_PATH_ENV: &str = "ASYNC_PUB_COUNTER_SNAPSHOT";
+pub const WAL_DIR_ENV: &str = "ASYNC_PUB_COUNTER_WAL";
+pub const RETENTION_ENV: &str = "ASYNC_PUB_COUNTER_RETENTION";
+
+pub struct CounterServerBuilder {
+    config: CounterConfig,
+    drain_timeout: Duration,
+    shutdown_on_signals: bool,
+    snapshot_path: Option<PathBuf>,
+    snapshot_interval: Duration,
+    wal_dir: Option<PathBuf>,
+    wal_fsync: FsyncPolicy,
+    retention: Retention,
+}
 
-    loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
+impl CounterServerBuilder {
+    /// Where to listen. Port 0 picks a free port, see `CounterServer::local_addr`.
+    pub fn config(mut self, config: CounterConfig) -> Self {
+        self.config = config;
+        self
+    }
 
-        let counters = counters.clone();
+    /// How long connections still open at shutdown get to apply what they have received
+    /// before they are closed
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
 
-        // For each client, spawn a new task
-        tokio::spawn(async move {
-            let (reader, mut _writer) = socket.split();
+    /// Shut down on SIGINT or SIGTERM. Off by default, leaving signals to the embedding application.
+    pub fn shutdown_on_signals(mut self, enabled: bool) -> Self {
+        self.shutdown_on_signals = enabled;
+        self
+    }
 
-            let length_delimited = FramedRead::new(reader, LengthDelimitedCodec::new());
+    /// Snapshot every series to this file periodically and after shutting down,
+    /// and reload it when binding
+    pub fn snapshot_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot_path = Some(path.into());
+        self
+    }
 
-            // Deserialize frames
-            let mut deserialized = tokio_serde::SymmetricallyFramed::new(
-                length_delimited,
-                SymmetricalJson::<CounterMessage>::default(),
-            );
+    /// How often to snapshot, once a minute by default
+    pub fn snapshot_interval(mut self, interval: Duration) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
 
-            // We could process each message on its own thread, but that is probably not efficient
-            while let Some(msg) = deserialized.try_next().await.unwrap() {
-                // Must get the time after the message is received
-                let server_epoch_minutes = get_epoc_minutes();
-
-                match msg {
-                    CounterMessage::Read(counter) => {
-                        let time_series = counters.read().unwrap();
-                        if let Some(time_series) = time_series.get(&counter) {
-                            let time_series = time_series.lock().unwrap();
-                            time_series.iter().flat_map(|bucket| bucket.data.iter()).for_each(
-                                |counter_state| {
-                                    println!(
-                                        "{} [{}]: {}",
-                                        counter, counter_state.epoch_minutes, counter_state.count
-                                    );
-                                },
-                            );
-                        }
+    /// Log every applied update to segment files in this directory, and replay them
+    /// when binding, so a crash loses no more than the fsync policy allows.
+    /// Each snapshot checkpoints the log, so it needs a snapshot path too.
+    /// Appends from every connection take turns on one lock, fsyncs included.
+    pub fn wal_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.wal_dir = Some(dir.into());
+        self
+    }
+
+    /// When logged updates are flushed to disk, at most once a second by default.
+    /// `FsyncPolicy::Always` limits the server to one update per disk flush.
+    pub fn wal_fsync(mut self, policy: FsyncPolicy) -> Self {
+        self.wal_fsync = policy;
+        self
+    }
+
+    /// How long series are kept at each resolution. A snapshot taken with other tiers
+    /// is reloaded into these, so changing them only needs a restart.
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub async fn bind(self) -> Result<CounterServer, Error> {
+        if self.wal_dir.is_some() && self.snapshot_path.is_none() {
+            return Err(Error::Config(
+                "a write-ahead log needs a snapshot path to checkpoint to".to_string(),
+            ));
+        }
+        let snapshot = match &self.snapshot_path {
+            Some(path) => counter_snapshot::read(path)?,
+            None => None,
+        };
+        let wal_lsn = snapshot.as_ref().map_or(0, |snapshot| snapshot.wal_lsn);
+        let server_epoch_seconds = get_epoc_seconds();
+        let mut metrics = match snapshot {
+            Some(snapshot) => Metrics::restore(snapshot, self.retention, server_epoch_seconds),
+            None => Metrics {
+                retention: self.retention,
+                ..Metrics::default()
+            },
+        };
+        if let Some(dir) = &self.wal_dir {
+            let (wal, records) = Wal::open(dir, self.wal_fsync, wal_lsn)?;
+            // Replay what was applied after the snapshot, in the order it was applied
+            for record in records {
+                apply_message(&metrics, record.message, server_epoch_seconds);
+            }
+            metrics.wal = Some(Mutex::new(wal));
+        }
+
+        let listener = TcpListener::bind(self.config.resolve().await?.as_slice()).await?;
+        let shutdown = CancellationToken::new();
+        if self.shutdown_on_signals {
+            cancel_on_signals(shutdown.clone())?;
+        }
+        Ok(CounterServer {
+            local_addr: listener.local_addr()?,
+            listener,
+            metrics: Arc::new(metrics),
+            shutdown,
+            drain_timeout: self.drain_timeout,
+            snapshot_path: self.snapshot_path,
+            snapshot_interval: self.snapshot_interval,
+        })
+    }
+}
+
+/// Cancel the token on the first SIGINT or SIGTERM
+fn cancel_on_signals(shutdown: CancellationToken) -> Result<(), Error> {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let terminated = terminate.recv();
+        #[cfg(not(unix))]
+        let terminated = std::future::pending::<Option<()>>();
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminated => {}
+        }
+        eprintln!("Counter server shutting down");
+        shutdown.cancel();
+    });
+    Ok(())
+}
+
+/// A bound counter server, run by awaiting `serve` on any tokio runtime
+pub struct CounterServer {
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+    snapshot_path: Option<PathBuf>,
+    snapshot_interval: Duration,
+}
+
+impl CounterServer {
+    pub fn builder() -> CounterServerBuilder {
+        CounterServerBuilder {
+            config: CounterConfig::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            shutdown_on_signals: false,
+            snapshot_path: None,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            wal_dir: None,
+            wal_fsync: DEFAULT_WAL_FSYNC,
+            retention: Retention::default(),
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Shuts the server down from another task, once `serve` owns it
+    pub fn handle(&self) -> CounterServerHandle {
+        CounterServerHandle {
+            local_addr: self.local_addr,
+            metrics: self.metrics.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Accept connections until shut down. Then stop listening, let each open connection
+    /// apply the messages it has received and close, for up to the drain timeout,
+    /// and take a last snapshot.
+    pub async fn serve(self) -> Result<(), Error> {
+        let mut connections = JoinSet::new();
+        let mut snapshots = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.snapshot_interval,
+            self.snapshot_interval,
+        );
+        // Syncs the write-ahead log for the `Interval` fsync policy, even while no updates arrive
+        let wal_sync_interval = self
+            .metrics
+            .wal
+            .as_ref()
+            .and_then(|wal| wal.lock().unwrap().sync_interval());
+        // Never polled without one, so any period will do
+        let wal_sync_period = wal_sync_interval.unwrap_or(self.snapshot_interval);
+        let mut wal_syncs = tokio::time::interval_at(
+            tokio::time::Instant::now() + wal_sync_period,
+            wal_sync_period,
+        );
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = wal_syncs.tick(), if wal_sync_interval.is_some() => {
+                    if let Err(e) = sync_wal(self.metrics.clone()).await {
+                        eprintln!("Failed to sync the counter server's log: {}", e);
                     }
-                    CounterMessage::Update(msg) => {
-                        msg.state.iter().for_each(|counter_state| {
-                            println!(
-                                "{} [{}]: {}",
-                                msg.counter, counter_state.epoch_minutes, counter_state.count
-                            );
-                        });
-        
-                        // Get the time series for the counter, creating it if it doesn't exist
-                        // NOTE: Not using a more natural if let else here because the read lock is held through the else block
-                        let time_series = counters
-                            .read()
-                            .unwrap()
-                            .get(&msg.counter)
-                            .map(|ts| ts.clone());
-
-                        let time_series = time_series.unwrap_or_else(|| {
-                            let time_series = Arc::new(Mutex::new(create_time_series()));
-                            counters
-                                .write()
-                                .unwrap()
-                                .insert(msg.counter.clone(), time_series.clone());
-                            time_series
-                        });
-
-                        update_time_series(
-                            &mut *time_series.lock().unwrap(),
-                            msg.state,
-                            server_epoch_minutes,
-                        );
+                }
+                _ = snapshots.tick(), if self.snapshot_path.is_some() => {
+                    let path = self.snapshot_path.clone().unwrap();
+                    if let Err(e) = save_snapshot(self.metrics.clone(), path).await {
+                        eprintln!("Failed to snapshot the counter server: {}", e);
                     }
                 }
+                accepted = self.listener.accept() => match accepted {
+                    Ok((socket, _)) => {
+                        connections.spawn(handle_connection(
+                            socket,
+                            self.metrics.clone(),
+                            self.shutdown.clone(),
+                        ));
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                },
+                // Reap finished connections as we go
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
             }
-        });
+        }
+
+        drop(self.listener);
+        let drained = tokio::time::timeout(self.drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            eprintln!(
+                "Closing {} connections still open after the drain timeout",
+                connections.len()
+            );
+            // Aborted between messages, so each message is applied whole or not at all
+            connections.shutdown().await;
+        }
+
+        if let Some(path) = self.snapshot_path {
+            save_snapshot(self.metrics, path).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Flush the records appended to the write-ahead log since its last sync
+async fn sync_wal(metrics: Arc<Metrics>) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || match &metrics.wal {
+        Some(wal) => wal.lock().unwrap().sync_pending(),
+        None => Ok(()),
+    })
+    .await
+    .map_err(io::Error::from)?
+}
+
+/// Write a snapshot. With a write-ahead log, this is a checkpoint: the snapshot records the
+/// last update it includes, and the log segments before it are removed once it is on disk.
+async fn save_snapshot(metrics: Arc<Metrics>, path: PathBuf) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let server_epoch_seconds = get_epoc_seconds();
+        let Some(wal) = &metrics.wal else {
+            return counter_snapshot::write(&path, &metrics.snapshot(server_epoch_seconds));
+        };
+        // No update is applied between taking the snapshot and starting a new segment
+        let (snapshot, sealed) = {
+            let mut wal = wal.lock().unwrap();
+            let mut snapshot = metrics.snapshot(server_epoch_seconds);
+            snapshot.wal_lsn = wal.last_lsn();
+            (snapshot, wal.rotate()?)
+        };
+        counter_snapshot::write(&path, &snapshot)?;
+        wal.lock().unwrap().remove_through(sealed)
+    })
+    .await
+    .map_err(io::Error::from)?
+}
+
+#[derive(Clone)]
+pub struct CounterServerHandle {
+    local_addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+}
+
+impl CounterServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// What the server has refused since it started
+    pub fn rejected(&self) -> Rejected {
+        let rejected = &self.metrics.rejected;
+        Rejected {
+            malformed: rejected.malformed.load(Ordering::Relaxed),
+            unregistered: rejected.unregistered.load(Ordering::Relaxed),
+            out_of_range: rejected.out_of_range.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Updates applied without being written to the write-ahead log since the server started.
+    /// A crash before the next snapshot loses them.
+    pub fn unlogged_updates(&self) -> u64 {
+        self.metrics.unlogged.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting connections and drain the open ones. `serve` returns once they have.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
     }
 }
 
-fn update_time_series(
-    time_series: &mut TimeSeries,
-    counter_message: Vec<CounterState>,
-    server_epoch_minutes: u64,
+/// Serve the connection until the client closes it or the server shuts down.
+/// At shutdown the frames already received are still applied, then the connection is closed.
+async fn handle_connection(
+    mut socket: TcpStream,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
 ) {
-    // TODO: Stop accepting messages that are too much older than the server time?
-    // For now, reject messages that are older than the youngest time series bucket
+    let (reader, writer) = socket.split();
 
-    // Update the existing time series buckets, so that none hold data past thier cutoff
-    shift_time_series(time_series, server_epoch_minutes);
+    let length_delimited = FramedRead::new(reader, LengthDelimitedCodec::new());
+
+    // Deserialize frames
+    let mut deserialized = tokio_serde::SymmetricallyFramed::new(
+        length_delimited,
+        SymmetricalJson::<CounterMessage>::default(),
+    );
+    // Responses to queries go back on the same connection
+    let mut responses = tokio_serde::SymmetricallyFramed::new(
+        FramedWrite::new(writer, LengthDelimitedCodec::new()),
+        SymmetricalJson::<CounterResponse>::default(),
+    );
+
+    // Ids the client registered for this connection
+    let mut registered: HashMap<u32, CounterKey> = HashMap::new();
+
+    // We could process each message on its own thread, but that is probably not efficient
+    loop {
+        let next = tokio::select! {
+            // Read first, so whatever has arrived is applied before closing
+            biased;
+            next = deserialized.try_next() => next,
+            _ = shutdown.cancelled() => break,
+        };
+        let msg = match next {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            // A frame that isn't a message is skipped. After an oversized frame
+            // the stream ends, so the connection closes at the next read.
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                metrics.rejected.malformed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Skipping a malformed frame: {}", e);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Closing a connection after a read error: {}", e);
+                break;
+            }
+        };
+        // Must get the time after the message is received
+        let server_epoch_seconds = get_epoc_seconds();
+
+        match msg {
+            CounterMessage::Register { id, counter } => {
+                registered.insert(id, counter);
+            }
+            CounterMessage::Query { id, query } => {
+                let result = answer(&metrics, query, server_epoch_seconds);
+                if let Err(e) = responses.send(CounterResponse { id, result }).await {
+                    eprintln!("Failed to send a query response: {}", e);
+                    break;
+                }
+            }
+            CounterMessage::Update(msg) => {
+                let Some(msg) = resolve(&registered, msg) else {
+                    metrics.rejected.unregistered(1);
+                    eprintln!("Update for an unregistered counter id");
+                    continue;
+                };
+                msg.state.iter().for_each(|counter_state| {
+                    println!(
+                        "{:?} [{}]: {}",
+                        msg.counter, counter_state.epoch_seconds, counter_state.count
+                    );
+                });
+
+                let applied = metrics.apply(CounterMessage::Update(msg), server_epoch_seconds);
+                metrics.rejected.out_of_range(applied);
+            }
+            CounterMessage::UpdateGauge(msg) => {
+                let Some(msg) = resolve(&registered, msg) else {
+                    metrics.rejected.unregistered(1);
+                    eprintln!("Update for an unregistered gauge id");
+                    continue;
+                };
+                let applied = metrics.apply(CounterMessage::UpdateGauge(msg), server_epoch_seconds);
+                metrics.rejected.out_of_range(applied);
+            }
+            CounterMessage::UpdateHistogram(msg) => {
+                let Some(msg) = resolve(&registered, msg) else {
+                    metrics.rejected.unregistered(1);
+                    eprintln!("Update for an unregistered histogram id");
+                    continue;
+                };
+                let applied =
+                    metrics.apply(CounterMessage::UpdateHistogram(msg), server_epoch_seconds);
+                metrics.rejected.out_of_range(applied);
+            }
+            CounterMessage::Batch(mut batch) => {
+                let unresolved = resolve_batch(&registered, &mut batch);
+                if unresolved > 0 {
+                    metrics.rejected.unregistered(unresolved);
+                    eprintln!("Batch has {} entries for unregistered ids", unresolved);
+                }
+                let applied = metrics.apply(CounterMessage::Batch(batch), server_epoch_seconds);
+                metrics.rejected.out_of_range(applied);
+            }
+        }
+    }
+}
+
+type SeriesMap<S> = HashMap<CounterKey, Arc<Mutex<TimeSeries<S>>>>;
+type SourceSeqs = Mutex<Sources>;
+
+/// Last applied sequence number for each update source
+#[derive(Default)]
+struct Sources {
+    seqs: HashMap<u64, SourceSeq>,
+    /// When idle sources were last removed
+    expired_seconds: u64,
+}
+
+struct SourceSeq {
+    seq: u64,
+    last_seen_seconds: u64,
+}
+
+impl Sources {
+    /// Sources as a snapshot saved them, seen when it was taken
+    fn restore(seqs: HashMap<u64, u64>, snapshot_epoch_seconds: u64) -> Sources {
+        let seqs = seqs
+            .into_iter()
+            .map(|(source, seq)| {
+                let seen = SourceSeq {
+                    seq,
+                    last_seen_seconds: snapshot_epoch_seconds,
+                };
+                (source, seen)
+            })
+            .collect();
+        Sources {
+            seqs,
+            expired_seconds: snapshot_epoch_seconds,
+        }
+    }
+
+    fn snapshot(&self) -> HashMap<u64, u64> {
+        self.seqs
+            .iter()
+            .map(|(source, last)| (*source, last.seq))
+            .collect()
+    }
+
+    /// Forget the sources idle for `SOURCE_IDLE_SECONDS`.
+    /// Sweeps at most once per idle period, so it costs little per update.
+    fn expire_idle(&mut self, server_epoch_seconds: u64) {
+        if server_epoch_seconds < self.expired_seconds + SOURCE_IDLE_SECONDS {
+            return;
+        }
+        self.seqs
+            .retain(|_, last| last.last_seen_seconds + SOURCE_IDLE_SECONDS > server_epoch_seconds);
+        self.expired_seconds = server_epoch_seconds;
+    }
+}
+
+/// Every series the server holds, shared by all connections
+#[derive(Default)]
+struct Metrics {
+    counters: RwLock<SeriesMap<CounterState>>,
+    gauges: RwLock<SeriesMap<GaugeState>>,
+    histograms: RwLock<SeriesMap<HistogramState>>,
+    sources: SourceSeqs,
+    rejected: RejectedCounts,
+    /// Updates applied since the last snapshot, if the server keeps a write-ahead log
+    wal: Option<Mutex<Wal>>,
+    /// Updates applied that the write-ahead log failed to record
+    unlogged: AtomicU64,
+    retention: Retention,
+}
+
+#[derive(Default)]
+struct RejectedCounts {
+    malformed: AtomicU64,
+    unregistered: AtomicU64,
+    out_of_range: AtomicU64,
+}
+
+impl RejectedCounts {
+    fn unregistered(&self, count: usize) {
+        self.unregistered.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Count the states an applied update dropped
+    fn out_of_range(&self, applied: Option<usize>) {
+        if let Some(dropped) = applied {
+            self.out_of_range
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+    }
+}
 
-    counter_message.into_iter().for_each(|counter_state| {
-        add_to_series(time_series, counter_state, server_epoch_minutes);
+impl Metrics {
+    /// Reload a snapshot taken at an earlier server time, aged to `server_epoch_seconds`
+    fn restore(snapshot: Snapshot, retention: Retention, server_epoch_seconds: u64) -> Metrics {
+        let taken = snapshot.epoch_seconds;
+        let now = server_epoch_seconds;
+        Metrics {
+            counters: restore_series(snapshot.counters, &retention, taken, now),
+            gauges: restore_series(snapshot.gauges, &retention, taken, now),
+            histograms: restore_series(snapshot.histograms, &retention, taken, now),
+            sources: Mutex::new(Sources::restore(snapshot.sources, taken)),
+            rejected: RejectedCounts::default(),
+            wal: None,
+            unlogged: AtomicU64::new(0),
+            retention,
+        }
+    }
+
+    /// Log the update, if the server keeps a write-ahead log, then apply it.
+    /// Returns how many states were out of range, like `apply_update`.
+    ///
+    /// An update the log fails to record is still applied and counted as unlogged.
+    /// Clients don't wait for an acknowledgement, so refusing it would lose it outright,
+    /// while applied it is kept by the next snapshot unless the server crashes first.
+    fn apply(&self, update: CounterMessage, server_epoch_seconds: u64) -> Option<usize> {
+        // Held while applying, so the log holds updates in the order they were applied.
+        // This serializes every connection's updates, and any fsync the policy calls for.
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock().unwrap());
+        if let Some(wal) = &mut wal {
+            if let Err(e) = wal.append(&update) {
+                self.unlogged.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Failed to log an update: {}", e);
+            }
+        }
+        apply_message(self, update, server_epoch_seconds)
+    }
+
+    fn snapshot(&self, server_epoch_seconds: u64) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            epoch_seconds: server_epoch_seconds,
+            counters: snapshot_series(&self.counters, server_epoch_seconds),
+            gauges: snapshot_series(&self.gauges, server_epoch_seconds),
+            histograms: snapshot_series(&self.histograms, server_epoch_seconds),
+            sources: self.sources.lock().unwrap().snapshot(),
+            wal_lsn: 0,
+        }
+    }
+}
+
+fn snapshot_series<S: IntervalState>(
+    counters: &RwLock<SeriesMap<S>>,
+    server_epoch_seconds: u64,
+) -> Vec<SeriesSnapshot<S>> {
+    // Don't hold the map's lock while locking each series
+    let series: Vec<(CounterKey, Arc<Mutex<TimeSeries<S>>>)> = counters
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(key, time_series)| (key.clone(), time_series.clone()))
+        .collect();
+    series
+        .into_iter()
+        .map(|(key, time_series)| {
+            let mut time_series = time_series.lock().unwrap();
+            shift_time_series(&mut time_series, server_epoch_seconds);
+            SeriesSnapshot {
+                key,
+                states: combine_by_interval([&*time_series]),
+            }
+        })
+        .collect()
+}
+
+/// Rebuild each series' buckets as they were when the snapshot was taken,
+/// then shift them to the current time
+fn restore_series<S: IntervalState>(
+    series: Vec<SeriesSnapshot<S>>,
+    retention: &Retention,
+    snapshot_epoch_seconds: u64,
+    server_epoch_seconds: u64,
+) -> RwLock<SeriesMap<S>> {
+    let restored = series
+        .into_iter()
+        .map(|series| {
+            let mut time_series = create_time_series(retention);
+            update_time_series(&mut time_series, series.states, snapshot_epoch_seconds);
+            shift_time_series(&mut time_series, server_epoch_seconds);
+            (series.key, Arc::new(Mutex::new(time_series)))
+        })
+        .collect();
+    RwLock::new(restored)
+}
+
+/// Counts of what the server refused, by reason
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rejected {
+    /// Frames that weren't a valid message
+    pub malformed: u64,
+    /// Updates, or batch entries, naming an id the connection never registered
+    pub unregistered: u64,
+    /// States too old for the oldest bucket, or too far ahead of the server clock
+    pub out_of_range: u64,
+}
+
+/// Replace a registered id with its key. None if the id was never registered on the connection.
+fn resolve<S>(
+    registered: &HashMap<u32, CounterKey>,
+    mut msg: UpdateMessage<S>,
+) -> Option<UpdateMessage<S>> {
+    resolve_ref(registered, &mut msg.counter).then_some(msg)
+}
+
+/// Resolve the ids of every entry in the batch. Returns how many were never registered.
+fn resolve_batch(registered: &HashMap<u32, CounterKey>, batch: &mut BatchMessage) -> usize {
+    let counters = batch.counters.iter_mut().map(|entry| &mut entry.counter);
+    let gauges = batch.gauges.iter_mut().map(|entry| &mut entry.counter);
+    let histograms = batch.histograms.iter_mut().map(|entry| &mut entry.counter);
+    counters
+        .chain(gauges)
+        .chain(histograms)
+        .map(|counter| resolve_ref(registered, counter))
+        .filter(|resolved| !resolved)
+        .count()
+}
+
+fn resolve_ref(registered: &HashMap<u32, CounterKey>, counter: &mut MetricRef) -> bool {
+    if let MetricRef::Id(id) = counter {
+        match registered.get(id) {
+            Some(key) => *counter = MetricRef::Key(key.clone()),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Whether the update is newer than the last one applied from its source, recording it if so
+fn is_new_update(sources: &SourceSeqs, source: u64, seq: u64, server_epoch_seconds: u64) -> bool {
+    if seq == 0 {
+        return true;
+    }
+    let mut sources = sources.lock().unwrap();
+    sources.expire_idle(server_epoch_seconds);
+    let last = sources.seqs.entry(source).or_insert(SourceSeq {
+        seq: 0,
+        last_seen_seconds: server_epoch_seconds,
     });
+    last.last_seen_seconds = last.last_seen_seconds.max(server_epoch_seconds);
+    if seq <= last.seq {
+        return false;
+    }
+    last.seq = seq;
+    true
+}
+
+/// Add the update's states to the metric's time series, returning how many states were
+/// out of range. None, without applying it, if the update is a resend of one already applied
+/// or names its metric by an unresolved id.
+fn apply_update<S: IntervalState>(
+    counters: &RwLock<SeriesMap<S>>,
+    sources: &SourceSeqs,
+    retention: &Retention,
+    msg: UpdateMessage<S>,
+    server_epoch_seconds: u64,
+) -> Option<usize> {
+    if msg.counter.key().is_none()
+        || !is_new_update(sources, msg.source, msg.seq, server_epoch_seconds)
+    {
+        return None;
+    }
+
+    let entry = BatchEntry {
+        counter: msg.counter,
+        state: msg.state,
+    };
+    Some(apply_entries(
+        counters,
+        retention,
+        vec![entry],
+        server_epoch_seconds,
+    ))
+}
+
+/// Apply an update message of any kind, with its metrics already resolved
+fn apply_message(
+    metrics: &Metrics,
+    update: CounterMessage,
+    server_epoch_seconds: u64,
+) -> Option<usize> {
+    match update {
+        CounterMessage::Update(msg) => apply_update(
+            &metrics.counters,
+            &metrics.sources,
+            &metrics.retention,
+            msg,
+            server_epoch_seconds,
+        ),
+        CounterMessage::UpdateGauge(msg) => apply_update(
+            &metrics.gauges,
+            &metrics.sources,
+            &metrics.retention,
+            msg,
+            server_epoch_seconds,
+        ),
+        CounterMessage::UpdateHistogram(msg) => apply_update(
+            &metrics.histograms,
+            &metrics.sources,
+            &metrics.retention,
+            msg,
+            server_epoch_seconds,
+        ),
+        CounterMessage::Batch(batch) => apply_batch(metrics, batch, server_epoch_seconds),
+        CounterMessage::Register { .. } | CounterMessage::Query { .. } => None,
+    }
+}
+
+/// Apply every entry of the batch, or none of them if the batch is a resend.
+/// Returns how many states were out of range, like `apply_update`.
+fn apply_batch(metrics: &Metrics, batch: BatchMessage, server_epoch_seconds: u64) -> Option<usize> {
+    if !is_new_update(
+        &metrics.sources,
+        batch.source,
+        batch.seq,
+        server_epoch_seconds,
+    ) {
+        return None;
+    }
+
+    let retention = &metrics.retention;
+    Some(
+        apply_entries(
+            &metrics.counters,
+            retention,
+            batch.counters,
+            server_epoch_seconds,
+        ) + apply_entries(
+            &metrics.gauges,
+            retention,
+            batch.gauges,
+            server_epoch_seconds,
+        ) + apply_entries(
+            &metrics.histograms,
+            retention,
+            batch.histograms,
+            server_epoch_seconds,
+        ),
+    )
+}
+
+/// Add each entry's states to its metric's time series, in one pass over the map.
+/// Entries naming an unresolved id are skipped. Returns how many states were out of range.
+fn apply_entries<S: IntervalState>(
+    counters: &RwLock<SeriesMap<S>>,
+    retention: &Retention,
+    entries: Vec<BatchEntry<S>>,
+    server_epoch_seconds: u64,
+) -> usize {
+    // Get the time series for each counter under one read lock,
+    // then create any that don't exist under one write lock
+    let mut found = Vec::with_capacity(entries.len());
+    let mut missing = Vec::new();
+    {
+        let counters = counters.read().unwrap();
+        for entry in entries {
+            let MetricRef::Key(counter) = entry.counter else {
+                continue;
+            };
+            match counters.get(&counter) {
+                Some(time_series) => found.push((time_series.clone(), entry.state)),
+                None => missing.push((counter, entry.state)),
+            }
+        }
+    }
+    if !missing.is_empty() {
+        let mut counters = counters.write().unwrap();
+        for (counter, state) in missing {
+            let time_series = counters
+                // Another connection may have created it between the locks
+                .entry(counter)
+                .or_insert_with(|| Arc::new(Mutex::new(create_time_series(retention))))
+                .clone();
+            found.push((time_series, state));
+        }
+    }
+
+    found
+        .into_iter()
+        .map(|(time_series, state)| {
+            update_time_series(
+                &mut time_series.lock().unwrap(),
+                state,
+                server_epoch_seconds,
+            )
+        })
+        .sum()
+}
+
+/// Answer a query as of the server time
+fn answer(metrics: &Metrics, query: Query, server_epoch_seconds: u64) -> QueryResult {
+    let result = match query {
+        Query::Read {
+            counter,
+            from_seconds,
+            to_seconds,
+        } => query_range(from_seconds, to_seconds).and_then(|range| {
+            series(
+                &metrics.counters,
+                &counter,
+                range,
+                &metrics.retention,
+                server_epoch_seconds,
+            )
+            .map(QueryResult::Counters)
+        }),
+        Query::Aggregate {
+            name,
+            labels,
+            from_seconds,
+            to_seconds,
+        } => query_range(from_seconds, to_seconds).and_then(|range| {
+            aggregate(
+                &metrics.counters,
+                name,
+                labels,
+                range,
+                &metrics.retention,
+                server_epoch_seconds,
+            )
+            .map(QueryResult::Counters)
+        }),
+        Query::ReadGauge {
+            gauge,
+            from_seconds,
+            to_seconds,
+        } => query_range(from_seconds, to_seconds).and_then(|range| {
+            series(
+                &metrics.gauges,
+                &gauge,
+                range,
+                &metrics.retention,
+                server_epoch_seconds,
+            )
+            .map(QueryResult::Gauges)
+        }),
+        Query::AggregateGauges {
+            name,
+            labels,
+            from_seconds,
+            to_seconds,
+        } => query_range(from_seconds, to_seconds).and_then(|range| {
+            aggregate(
+                &metrics.gauges,
+                name,
+                labels,
+                range,
+                &metrics.retention,
+                server_epoch_seconds,
+            )
+            .map(QueryResult::Gauges)
+        }),
+        Query::ReadHistogram {
+            histogram,
+            from_seconds,
+            to_seconds,
+        } => query_range(from_seconds, to_seconds).and_then(|range| {
+            series(
+                &metrics.histograms,
+                &histogram,
+                range,
+                &metrics.retention,
+                server_epoch_seconds,
+            )
+            .map(QueryResult::Histograms)
+        }),
+        Query::AggregateHistograms {
+            name,
+            labels,
+            from_seconds,
+            to_seconds,
+        } => query_range(from_seconds, to_seconds).and_then(|range| {
+            aggregate(
+                &metrics.histograms,
+                name,
+                labels,
+                range,
+                &metrics.retention,
+                server_epoch_seconds,
+            )
+            .map(QueryResult::Histograms)
+        }),
+        Query::Percentiles {
+            name,
+            labels,
+            from_seconds,
+            to_seconds,
+            quantiles,
+        } => query_range(from_seconds, to_seconds).and_then(|range| {
+            if let Some(q) = quantiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+                Err(QueryError::InvalidQuery(format!(
+                    "quantile {} is outside [0, 1]",
+                    q
+                )))
+            } else if !any_matching(&metrics.histograms, &name, &labels) {
+                Err(QueryError::NoMatchingMetrics { name, labels })
+            } else {
+                Ok(QueryResult::Percentiles(percentiles(
+                    &metrics.histograms,
+                    &name,
+                    &labels,
+                    range,
+                    &quantiles,
+                    server_epoch_seconds,
+                )))
+            }
+        }),
+        Query::List => Ok(QueryResult::Metrics(MetricList {
+            counters: keys(&metrics.counters),
+            gauges: keys(&metrics.gauges),
+            histograms: keys(&metrics.histograms),
+        })),
+    };
+    result.unwrap_or_else(QueryResult::Error)
+}
+
+/// The intervals a query covers, which must not be empty
+fn query_range(from_seconds: u64, to_seconds: u64) -> Result<Range<u64>, QueryError> {
+    if from_seconds >= to_seconds {
+        return Err(QueryError::InvalidQuery(format!(
+            "empty range {}..{}",
+            from_seconds, to_seconds
+        )));
+    }
+    Ok(from_seconds..to_seconds)
+}
+
+fn series<S: IntervalState>(
+    counters: &RwLock<SeriesMap<S>>,
+    counter: &CounterKey,
+    range: Range<u64>,
+    retention: &Retention,
+    server_epoch_seconds: u64,
+) -> Result<Series<S>, QueryError> {
+    if !counters.read().unwrap().contains_key(counter) {
+        return Err(QueryError::UnknownMetric(counter.clone()));
+    }
+    Ok(Series {
+        states: within(read_series(counters, counter, server_epoch_seconds), range),
+        resolutions: resolutions(retention, server_epoch_seconds),
+    })
+}
+
+fn aggregate<S: IntervalState>(
+    counters: &RwLock<SeriesMap<S>>,
+    name: String,
+    labels: Labels,
+    range: Range<u64>,
+    retention: &Retention,
+    server_epoch_seconds: u64,
+) -> Result<Series<S>, QueryError> {
+    if !any_matching(counters, &name, &labels) {
+        return Err(QueryError::NoMatchingMetrics { name, labels });
+    }
+    Ok(Series {
+        states: within(
+            aggregate_series(counters, &name, &labels, server_epoch_seconds),
+            range,
+        ),
+        resolutions: resolutions(retention, server_epoch_seconds),
+    })
+}
+
+/// The states of intervals starting in the range
+fn within<S: IntervalState>(mut states: Vec<S>, range: Range<u64>) -> Vec<S> {
+    states.retain(|state| range.contains(&state.epoch_seconds()));
+    states
+}
+
+fn keys<S>(counters: &RwLock<SeriesMap<S>>) -> Vec<CounterKey> {
+    let mut keys: Vec<CounterKey> = counters.read().unwrap().keys().cloned().collect();
+    keys.sort();
+    keys
+}
+
+fn any_matching<S>(counters: &RwLock<SeriesMap<S>>, name: &str, labels: &Labels) -> bool {
+    counters
+        .read()
+        .unwrap()
+        .keys()
+        .any(|key| key.matches(name, labels))
+}
+
+/// The resolution of each bucket of a series shifted to the server time, oldest to newest
+fn resolutions(retention: &Retention, server_epoch_seconds: u64) -> Vec<Resolution> {
+    let mut resolutions: Vec<Resolution> = retention
+        .cutoffs()
+        .map(|(tier, cutoff)| Resolution {
+            from_seconds: server_epoch_seconds.saturating_sub(cutoff),
+            interval_seconds: tier.interval_seconds,
+        })
+        .collect();
+    resolutions.reverse();
+    resolutions
+}
+
+/// The states of a single label set, oldest to newest
+fn read_series<S: IntervalState>(
+    counters: &RwLock<SeriesMap<S>>,
+    counter: &CounterKey,
+    server_epoch_seconds: u64,
+) -> Vec<S> {
+    let time_series = counters.read().unwrap().get(counter).cloned();
+    match time_series {
+        Some(time_series) => {
+            let mut time_series = time_series.lock().unwrap();
+            shift_time_series(&mut time_series, server_epoch_seconds);
+            combine_by_interval([&*time_series])
+        }
+        None => Vec::new(),
+    }
+}
+
+/// The combined states of every label set with the name and at least the given labels,
+/// oldest to newest. An empty label filter aggregates across all of the metric's label sets.
+fn aggregate_series<S: IntervalState>(
+    counters: &RwLock<SeriesMap<S>>,
+    name: &str,
+    labels: &Labels,
+    server_epoch_seconds: u64,
+) -> Vec<S> {
+    let matching: Vec<Arc<Mutex<TimeSeries<S>>>> = counters
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(key, _)| key.matches(name, labels))
+        .map(|(_, time_series)| time_series.clone())
+        .collect();
+
+    // Copy each series under its own lock rather than holding them all, which could deadlock
+    // with another query locking the same series in a different order.
+    // Each is shifted to the same time first, so that their intervals line up.
+    let copies: Vec<TimeSeries<S>> = matching
+        .iter()
+        .map(|time_series| {
+            let mut time_series = time_series.lock().unwrap();
+            shift_time_series(&mut time_series, server_epoch_seconds);
+            time_series.clone()
+        })
+        .collect();
+    combine_by_interval(&copies)
+}
+
+/// Quantiles of the matching histograms merged over the intervals starting in the range.
+/// None for each quantile if nothing was recorded.
+fn percentiles(
+    histograms: &RwLock<SeriesMap<HistogramState>>,
+    name: &str,
+    labels: &Labels,
+    range: Range<u64>,
+    quantiles: &[f64],
+    server_epoch_seconds: u64,
+) -> Vec<Option<f64>> {
+    let merged = within(
+        aggregate_series(histograms, name, labels, server_epoch_seconds),
+        range,
+    )
+    .into_iter()
+    .reduce(|mut merged, state| {
+        merged.merge(state);
+        merged
+    });
+    quantiles
+        .iter()
+        .map(|q| merged.as_ref().and_then(|merged| merged.quantile(*q)))
+        .collect()
+}
+
+fn combine_by_interval<'a, S: IntervalState + 'a>(
+    series: impl IntoIterator<Item = &'a TimeSeries<S>>,
+) -> Vec<S> {
+    let mut combined: BTreeMap<u64, S> = BTreeMap::new();
+    series
+        .into_iter()
+        .flat_map(|time_series| time_series.iter())
+        .flat_map(|bucket| bucket.data.iter())
+        .for_each(|state| match combined.entry(state.epoch_seconds()) {
+            Entry::Occupied(mut entry) => entry.get_mut().combine(state.clone()),
+            Entry::Vacant(entry) => {
+                entry.insert(state.clone());
+            }
+        });
+    combined.into_values().collect()
+}
+
+/// Returns how many states were dropped for being older than the oldest bucket
+/// or too far ahead of the server clock
+fn update_time_series<S: IntervalState>(
+    time_series: &mut TimeSeries<S>,
+    counter_message: Vec<S>,
+    server_epoch_seconds: u64,
+) -> usize {
+    // Update the existing time series buckets, so that none hold data past thier cutoff
+    shift_time_series(time_series, server_epoch_seconds);
+
+    let mut dropped = 0;
+    for counter_state in counter_message {
+        if counter_state.epoch_seconds() > server_epoch_seconds + MAX_CLOCK_SKEW_SECONDS
+            || !add_to_series(time_series, counter_state, server_epoch_seconds)
+        {
+            dropped += 1;
+        }
+    }
+    dropped
 }
 
 /// Update the existing time series buckets, so that none hold data past thier cutoff
-fn shift_time_series(time_series: &mut TimeSeries, server_epoch_minutes: u64) {
+fn shift_time_series<S: IntervalState>(time_series: &mut TimeSeries<S>, server_epoch_seconds: u64) {
     for i in (0..time_series.len()).rev() {
         // Oldest to earliest bucket
         let (younger, older) = time_series.split_at_mut(i + 1);
         let bucket = younger.last_mut().unwrap();
         // Look at the oldest interval
-        while bucket.data.front_mut().map_or(false, |last_state| {
-            bucket.cutoff_minutes < server_epoch_minutes - last_state.epoch_minutes
+        while bucket.data.back().is_some_and(|oldest_state| {
+            bucket.cutoff_seconds
+                < server_epoch_seconds.saturating_sub(oldest_state.epoch_seconds())
         }) {
             let graduated_state = bucket.data.pop_back().unwrap();
-            // We have one or more intervals that are too old for bucket
-            add_to_series(older, graduated_state, server_epoch_minutes);
+            // We have one or more intervals that are too old for bucket.
+            // Past the oldest bucket they expire.
+            add_to_series(older, graduated_state, server_epoch_seconds);
         }
     }
 }
 
-fn add_to_series(
-    time_series: &mut [TimeBucket],
-    counter_state: CounterState,
-    server_epoch_minutes: u64,
-) {
+/// False if the counter state is too old for any time series bucket
+fn add_to_series<S: IntervalState>(
+    time_series: &mut [TimeBucket<S>],
+    counter_state: S,
+    server_epoch_seconds: u64,
+) -> bool {
     if time_series.is_empty() {
         // Happens when the counter state is too old for any time series bucket
-        return;
+        return false;
     }
 
-    if time_series[0].cutoff_minutes < server_epoch_minutes - counter_state.epoch_minutes {
+    // States slightly ahead of the server clock count as current
+    if time_series[0].cutoff_seconds
+        < server_epoch_seconds.saturating_sub(counter_state.epoch_seconds())
+    {
         // The counter state is too old for the current time series bucket
-        add_to_series(&mut time_series[1..], counter_state, server_epoch_minutes);
-        return;
+        return add_to_series(&mut time_series[1..], counter_state, server_epoch_seconds);
     }
 
     // Most often the counter state will be added to the newest bucket
     let bucket = &mut time_series[0];
-    add_to_bucket(bucket, counter_state, server_epoch_minutes);
+    add_to_bucket(bucket, counter_state, server_epoch_seconds)
 }
 
-fn add_to_bucket(
-    bucket: &mut TimeBucket,
-    mut counter_state: CounterState,
-    server_epoch_minutes: u64,
-) {
-    if server_epoch_minutes - counter_state.epoch_minutes > bucket.cutoff_minutes {
-        panic!("Counter state is too old for the bucket");
+/// False if the counter state is too old for the bucket
+fn add_to_bucket<S: IntervalState>(
+    bucket: &mut TimeBucket<S>,
+    mut counter_state: S,
+    server_epoch_seconds: u64,
+) -> bool {
+    let epoch_seconds = counter_state.epoch_seconds();
+    if server_epoch_seconds.saturating_sub(epoch_seconds) > bucket.cutoff_seconds {
+        return false;
     }
 
-    for i in (0..bucket.data.len()).rev() {
+    // Intervals start at multiples of the bucket's interval
+    let interval_start = epoch_seconds - epoch_seconds % bucket.interval_seconds;
+    for i in 0..bucket.data.len() {
         // Handle updates of any age, but generally expect the newest state to be updated
         // i.e. Return after the first iteration
         let interval_state = &mut bucket.data[i];
-        if counter_state.epoch_minutes < interval_state.epoch_minutes {
-            // New state comes before the current interval
-            // Give the new counter state an aligned minute value
-            counter_state.epoch_minutes -= counter_state.epoch_minutes % bucket.interval_minutes;
-            if i == bucket.data.len() - 1 {
-                // The new state will be the newest in the bucket
-                bucket.data.push_back(counter_state);
-                return;
-            } else {
-                bucket.data.insert(i + 1, counter_state);
-            }
-            return;
-        } else if interval_state.epoch_minutes + bucket.interval_minutes
-            > counter_state.epoch_minutes
-        {
-            // New state comes falls into the current interval
-            interval_state.count += counter_state.count;
-            return;
+        if interval_start > interval_state.epoch_seconds() {
+            // New state comes after the current interval
+            counter_state.set_epoch_seconds(interval_start);
+            bucket.data.insert(i, counter_state);
+            return true;
+        } else if interval_start == interval_state.epoch_seconds() {
+            // New state falls into the current interval
+            interval_state.merge(counter_state);
+            return true;
         }
     }
-    counter_state.epoch_minutes -= counter_state.epoch_minutes % bucket.interval_minutes;
+    counter_state.set_epoch_seconds(interval_start);
     // The new state will be the oldest in the bucket
-    bucket.data.push_front(counter_state);
-}
-
-fn create_time_series() -> TimeSeries {
-    let mut series_vec = Vec::with_capacity(TIME_BUCKET_COUNT);
-    let mut cutoff = 0;
-    for spec in TIME_BUCKET_SPECS.iter() {
-        cutoff += spec.interval_minutes * spec.interval_count as u64;
-        series_vec.push(TimeBucket {
-            interval_minutes: spec.interval_minutes,
-            cutoff_minutes: cutoff,
-            data: VecDeque::with_capacity(spec.interval_count),
-        });
-    }
+    bucket.data.push_back(counter_state);
+    true
+}
 
-    <TimeSeries>::try_from(series_vec)
-        .unwrap_or_else(|_| panic!("Failed to create time series buckets"))
+fn create_time_series<S>(retention: &Retention) -> TimeSeries<S> {
+    retention
+        .cutoffs()
+        .map(|(tier, cutoff)| TimeBucket {
+            interval_seconds: tier.interval_seconds,
+            cutoff_seconds: cutoff,
+            data: VecDeque::with_capacity(tier.interval_count),
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
+    use tokio_util::bytes::Bytes;
+
     use super::*;
+    use crate::counter_client::CounterClient;
+    use crate::counter_retention::RetentionTier;
+    use crate::counter_types::CounterUpdateMessage;
+
+    const MINUTE: u64 = 60;
+    const HOUR: u64 = 60 * MINUTE;
 
     #[test]
     fn time_series_creation() {
-        let ts = create_time_series();
-        assert_eq!(ts.len(), TIME_BUCKET_COUNT);
-        assert_eq!(ts[0].interval_minutes, 1);
-        assert_eq!(ts[1].interval_minutes, 5);
-        assert_eq!(ts[2].interval_minutes, 15);
-        assert_eq!(ts[3].interval_minutes, 60);
-        assert_eq!(ts[4].interval_minutes, 3 * 60);
-
-        assert_eq!(ts[0].cutoff_minutes, 6 * 60);
-        assert_eq!(ts[1].cutoff_minutes, (6 * 60) + (18 * 60));
-        assert_eq!(ts[2].cutoff_minutes, (6 * 60) + (18 * 60) + (5 * 24 * 60));
+        let ts: TimeSeries<CounterState> = create_time_series(&Retention::default());
+        assert_eq!(ts.len(), Retention::default().tiers().len());
+        assert_eq!(ts[0].interval_seconds, MINUTE);
+        assert_eq!(ts[1].interval_seconds, 5 * MINUTE);
+        assert_eq!(ts[2].interval_seconds, 15 * MINUTE);
+        assert_eq!(ts[3].interval_seconds, HOUR);
+        assert_eq!(ts[4].interval_seconds, 3 * HOUR);
+
+        assert_eq!(ts[0].cutoff_seconds, 6 * HOUR);
+        assert_eq!(ts[1].cutoff_seconds, (6 * HOUR) + (18 * HOUR));
+        assert_eq!(
+            ts[2].cutoff_seconds,
+            (6 * HOUR) + (18 * HOUR) + (5 * 24 * HOUR)
+        );
         assert_eq!(
-            ts[3].cutoff_minutes,
-            (6 * 60) + (18 * 60) + (5 * 24 * 60) + (23 * 24 * 60)
+            ts[3].cutoff_seconds,
+            (6 * HOUR) + (18 * HOUR) + (5 * 24 * HOUR) + (23 * 24 * HOUR)
         );
         assert_eq!(
-            ts[4].cutoff_minutes,
-            (6 * 60) + (18 * 60) + (5 * 24 * 60) + (23 * 24 * 60) + (3 * 60 * 8 * 60)
+            ts[4].cutoff_seconds,
+            (6 * HOUR) + (18 * HOUR) + (5 * 24 * HOUR) + (23 * 24 * HOUR) + (3 * 8 * 60 * HOUR)
         );
 
         assert_eq!(ts[0].data.capacity(), 6 * 60);
@@ -288,126 +1339,126 @@ mod tests {
     #[test]
     fn add_to_empty_1min_bucket() {
         let mut bucket = TimeBucket {
-            interval_minutes: 1,
-            cutoff_minutes: 6 * 60,
+            interval_seconds: MINUTE,
+            cutoff_seconds: 6 * HOUR,
             data: VecDeque::with_capacity(6 * 60),
         };
 
         let counter_state = CounterState {
-            epoch_minutes: 5,
+            epoch_seconds: 5 * MINUTE,
             count: 3,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 6);
+        add_to_bucket(&mut bucket, counter_state, 6 * MINUTE);
 
         assert_eq!(bucket.data.len(), 1);
-        assert_eq!(bucket.data[0].epoch_minutes, 5);
+        assert_eq!(bucket.data[0].epoch_seconds, 5 * MINUTE);
         assert_eq!(bucket.data[0].count, 3);
     }
 
     #[test]
     fn add_to_empty_1min_bucket_multiple() {
         let mut bucket = TimeBucket {
-            interval_minutes: 1,
-            cutoff_minutes: 6 * 60,
+            interval_seconds: MINUTE,
+            cutoff_seconds: 6 * HOUR,
             data: VecDeque::with_capacity(6 * 60),
         };
 
         let counter_state = CounterState {
-            epoch_minutes: 1,
+            epoch_seconds: MINUTE,
             count: 1,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 1);
+        add_to_bucket(&mut bucket, counter_state, MINUTE);
 
         assert_eq!(bucket.data.len(), 1);
-        assert_eq!(bucket.data[0].epoch_minutes, 1);
+        assert_eq!(bucket.data[0].epoch_seconds, MINUTE);
         assert_eq!(bucket.data[0].count, 1);
 
         let counter_state = CounterState {
-            epoch_minutes: 4,
+            epoch_seconds: 4 * MINUTE,
             count: 1,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 4);
+        add_to_bucket(&mut bucket, counter_state, 4 * MINUTE);
 
         assert_eq!(bucket.data.len(), 2);
-        assert_eq!(bucket.data[0].epoch_minutes, 4);
+        assert_eq!(bucket.data[0].epoch_seconds, 4 * MINUTE);
         assert_eq!(bucket.data[0].count, 1);
-        assert_eq!(bucket.data[1].epoch_minutes, 1);
+        assert_eq!(bucket.data[1].epoch_seconds, MINUTE);
         assert_eq!(bucket.data[1].count, 1);
 
         let counter_state = CounterState {
-            epoch_minutes: 1,
+            epoch_seconds: MINUTE,
             count: 1,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 4);
+        add_to_bucket(&mut bucket, counter_state, 4 * MINUTE);
 
         assert_eq!(bucket.data.len(), 2);
-        assert_eq!(bucket.data[0].epoch_minutes, 4);
+        assert_eq!(bucket.data[0].epoch_seconds, 4 * MINUTE);
         assert_eq!(bucket.data[0].count, 1);
-        assert_eq!(bucket.data[1].epoch_minutes, 1);
+        assert_eq!(bucket.data[1].epoch_seconds, MINUTE);
         assert_eq!(bucket.data[1].count, 2);
     }
 
     #[test]
     fn add_to_empty_3minute_bucket_multiple() {
         let mut bucket = TimeBucket {
-            interval_minutes: 3,
-            cutoff_minutes: 6 * 60,
+            interval_seconds: 3 * MINUTE,
+            cutoff_seconds: 6 * HOUR,
             data: VecDeque::with_capacity(6 * 60 / 3),
         };
 
         let counter_state = CounterState {
-            epoch_minutes: 1,
+            epoch_seconds: MINUTE,
             count: 1,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 1);
+        add_to_bucket(&mut bucket, counter_state, MINUTE);
 
         assert_eq!(bucket.data.len(), 1);
         // 1 min in the 3 min bucket goes into the 0-2 min interval
-        assert_eq!(bucket.data[0].epoch_minutes, 0);
+        assert_eq!(bucket.data[0].epoch_seconds, 0);
         assert_eq!(bucket.data[0].count, 1);
 
         let counter_state = CounterState {
-            epoch_minutes: 4,
+            epoch_seconds: 4 * MINUTE,
             count: 1,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 4);
+        add_to_bucket(&mut bucket, counter_state, 4 * MINUTE);
 
         assert_eq!(bucket.data.len(), 2);
-        assert_eq!(bucket.data[0].epoch_minutes, 3);
+        assert_eq!(bucket.data[0].epoch_seconds, 3 * MINUTE);
         assert_eq!(bucket.data[0].count, 1);
-        assert_eq!(bucket.data[1].epoch_minutes, 0);
+        assert_eq!(bucket.data[1].epoch_seconds, 0);
         assert_eq!(bucket.data[1].count, 1);
 
         let counter_state = CounterState {
-            epoch_minutes: 1,
+            epoch_seconds: MINUTE,
             count: 1,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 4);
+        add_to_bucket(&mut bucket, counter_state, 4 * MINUTE);
 
         assert_eq!(bucket.data.len(), 2);
-        assert_eq!(bucket.data[0].epoch_minutes, 3);
+        assert_eq!(bucket.data[0].epoch_seconds, 3 * MINUTE);
         assert_eq!(bucket.data[0].count, 1);
-        assert_eq!(bucket.data[1].epoch_minutes, 0);
+        assert_eq!(bucket.data[1].epoch_seconds, 0);
         assert_eq!(bucket.data[1].count, 2);
 
         let counter_state = CounterState {
-            epoch_minutes: 5,
+            epoch_seconds: 5 * MINUTE,
             count: 7,
         };
 
-        add_to_bucket(&mut bucket, counter_state, 5);
+        add_to_bucket(&mut bucket, counter_state, 5 * MINUTE);
 
         assert_eq!(bucket.data.len(), 2);
-        assert_eq!(bucket.data[0].epoch_minutes, 3);
+        assert_eq!(bucket.data[0].epoch_seconds, 3 * MINUTE);
         assert_eq!(bucket.data[0].count, 8);
-        assert_eq!(bucket.data[1].epoch_minutes, 0);
+        assert_eq!(bucket.data[1].epoch_seconds, 0);
         assert_eq!(bucket.data[1].count, 2);
     }
 
@@ -415,113 +1466,113 @@ mod tests {
     fn add_to_0len_series() {
         let mut series = Vec::new();
         let counter_state = CounterState {
-            epoch_minutes: 1,
+            epoch_seconds: MINUTE,
             count: 1,
         };
 
-        add_to_series(&mut series, counter_state, 1);
+        add_to_series(&mut series, counter_state, MINUTE);
 
         assert_eq!(series.len(), 0);
     }
 
     #[test]
     fn add_to_standard_series() {
-        let mut series = create_time_series();
+        let mut series = create_time_series(&Retention::default());
         let counter_state = CounterState {
-            epoch_minutes: 1,
+            epoch_seconds: MINUTE,
             count: 1,
         };
 
-        add_to_series(&mut series, counter_state, 1);
+        add_to_series(&mut series, counter_state, MINUTE);
 
         assert_eq!(series[0].data.len(), 1);
-        assert_eq!(series[0].data[0].epoch_minutes, 1);
+        assert_eq!(series[0].data[0].epoch_seconds, MINUTE);
         assert_eq!(series[0].data[0].count, 1);
     }
 
     #[test]
     fn add_to_standard_series_multiple_first_bucket() {
-        let mut series = create_time_series();
+        let mut series = create_time_series(&Retention::default());
         let counter_state = CounterState {
-            epoch_minutes: 1,
+            epoch_seconds: MINUTE,
             count: 1,
         };
 
-        add_to_series(&mut series, counter_state, 1);
+        add_to_series(&mut series, counter_state, MINUTE);
 
         assert_eq!(series[0].data.len(), 1);
-        assert_eq!(series[0].data[0].epoch_minutes, 1);
+        assert_eq!(series[0].data[0].epoch_seconds, MINUTE);
         assert_eq!(series[0].data[0].count, 1);
 
         let counter_state = CounterState {
-            epoch_minutes: 2,
+            epoch_seconds: 2 * MINUTE,
             count: 1,
         };
 
-        add_to_series(&mut series, counter_state, 2);
+        add_to_series(&mut series, counter_state, 2 * MINUTE);
 
         assert_eq!(series[0].data.len(), 2);
-        assert_eq!(series[0].data[0].epoch_minutes, 2);
+        assert_eq!(series[0].data[0].epoch_seconds, 2 * MINUTE);
         assert_eq!(series[0].data[0].count, 1);
-        assert_eq!(series[0].data[1].epoch_minutes, 1);
+        assert_eq!(series[0].data[1].epoch_seconds, MINUTE);
         assert_eq!(series[0].data[1].count, 1);
     }
 
     #[test]
     fn add_to_standard_series_multiple_multiple_buckets() {
-        const START_POINT: u64 = 18 * 60;
+        const START_POINT: u64 = 18 * HOUR;
 
-        let mut series = create_time_series();
+        let mut series = create_time_series(&Retention::default());
         let counter_state = CounterState {
-            epoch_minutes: START_POINT,
+            epoch_seconds: START_POINT,
             count: 1,
         };
 
         add_to_series(&mut series, counter_state, START_POINT);
 
         assert_eq!(series[0].data.len(), 1);
-        assert_eq!(series[0].data[0].epoch_minutes, START_POINT);
+        assert_eq!(series[0].data[0].epoch_seconds, START_POINT);
         assert_eq!(series[0].data[0].count, 1);
         assert!(series[1..].iter().all(|bucket| bucket.data.is_empty()));
 
         let counter_state = CounterState {
-            epoch_minutes: START_POINT + 1,
+            epoch_seconds: START_POINT + MINUTE,
             count: 2,
         };
 
-        add_to_series(&mut series, counter_state, START_POINT + 1);
+        add_to_series(&mut series, counter_state, START_POINT + MINUTE);
 
         assert_eq!(series[0].data.len(), 2);
-        assert_eq!(series[0].data[0].epoch_minutes, START_POINT + 1);
+        assert_eq!(series[0].data[0].epoch_seconds, START_POINT + MINUTE);
         assert_eq!(series[0].data[0].count, 2);
-        assert_eq!(series[0].data[1].epoch_minutes, START_POINT);
+        assert_eq!(series[0].data[1].epoch_seconds, START_POINT);
         assert_eq!(series[0].data[1].count, 1);
         assert!(series[1..].iter().all(|bucket| bucket.data.is_empty()));
 
         let counter_state = CounterState {
-            epoch_minutes: 0, // Much older than the first bucket
+            epoch_seconds: 0, // Much older than the first bucket
             count: 5,
         };
 
-        add_to_series(&mut series, counter_state, START_POINT + 1);
+        add_to_series(&mut series, counter_state, START_POINT + MINUTE);
 
         assert_eq!(series[0].data.len(), 2);
-        assert_eq!(series[0].data[0].epoch_minutes, START_POINT + 1);
+        assert_eq!(series[0].data[0].epoch_seconds, START_POINT + MINUTE);
         assert_eq!(series[0].data[0].count, 2);
-        assert_eq!(series[0].data[1].epoch_minutes, START_POINT);
+        assert_eq!(series[0].data[1].epoch_seconds, START_POINT);
         assert_eq!(series[0].data[1].count, 1);
         assert_eq!(series[1].data.len(), 1);
-        assert_eq!(series[1].data[0].epoch_minutes, 0);
+        assert_eq!(series[1].data[0].epoch_seconds, 0);
         assert_eq!(series[1].data[0].count, 5);
         assert!(series[2..].iter().all(|bucket| bucket.data.is_empty()));
     }
 
     #[test]
     fn add_to_standard_series_past_final_cutoff() {
-        let mut series = create_time_series();
-        let start_point = series[TIME_BUCKET_COUNT - 1].cutoff_minutes + 100;
+        let mut series = create_time_series(&Retention::default());
+        let start_point = series[series.len() - 1].cutoff_seconds + 100;
         let counter_state = CounterState {
-            epoch_minutes: 10,
+            epoch_seconds: 10,
             count: 1,
         };
 
@@ -530,24 +1581,1008 @@ mod tests {
         assert!(series.iter().all(|bucket| bucket.data.is_empty()));
     }
 
+    #[test]
+    fn resent_updates_are_applied_once() {
+        let counters = RwLock::new(HashMap::new());
+        let sources = SourceSeqs::default();
+        let update = |source, seq, count| CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds: 10,
+                count,
+            }],
+            source,
+            seq,
+        };
+
+        assert_eq!(
+            apply_update(
+                &counters,
+                &sources,
+                &Retention::default(),
+                update(1, 1, 1),
+                10
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            apply_update(
+                &counters,
+                &sources,
+                &Retention::default(),
+                update(1, 2, 3),
+                10
+            ),
+            Some(0)
+        );
+        // Resend of seq 2
+        assert_eq!(
+            apply_update(
+                &counters,
+                &sources,
+                &Retention::default(),
+                update(1, 2, 3),
+                10
+            ),
+            None
+        );
+        // Another source's sequence is independent
+        assert_eq!(
+            apply_update(
+                &counters,
+                &sources,
+                &Retention::default(),
+                update(2, 1, 4),
+                10
+            ),
+            Some(0)
+        );
+
+        let counters = counters.read().unwrap();
+        let series = counters[&"a".into()].lock().unwrap();
+        assert_eq!(series[0].data.len(), 1);
+        assert_eq!(series[0].data[0].count, 8);
+    }
+
+    #[test]
+    fn idle_sources_are_forgotten() {
+        let sources = SourceSeqs::default();
+        assert!(is_new_update(&sources, 1, 5, 10));
+        assert!(is_new_update(&sources, 2, 1, 10));
+        // Source 2 stays active while source 1 goes quiet
+        let later = 10 + SOURCE_IDLE_SECONDS - 1;
+        assert!(is_new_update(&sources, 2, 2, later));
+        assert!(!is_new_update(&sources, 1, 5, later));
+        assert_eq!(sources.lock().unwrap().seqs.len(), 2);
+
+        // The resend at `later` counts as activity, so source 1 is only forgotten once idle from then
+        assert!(is_new_update(
+            &sources,
+            2,
+            3,
+            later + SOURCE_IDLE_SECONDS - 1
+        ));
+        assert_eq!(sources.lock().unwrap().seqs.len(), 2);
+        assert!(is_new_update(&sources, 2, 4, later + SOURCE_IDLE_SECONDS));
+        assert_eq!(
+            sources.lock().unwrap().seqs.keys().collect::<Vec<_>>(),
+            vec![&2]
+        );
+        // A forgotten source starts over
+        assert!(is_new_update(&sources, 1, 1, later + SOURCE_IDLE_SECONDS));
+    }
+
+    #[test]
+    fn registered_ids_resolve_to_keys() {
+        let registered = HashMap::from([(1, CounterKey::new("a"))]);
+        let update = |counter: MetricRef| CounterUpdateMessage {
+            counter,
+            state: vec![],
+            source: 0,
+            seq: 0,
+        };
+
+        let resolved = resolve(&registered, update(MetricRef::Id(1))).unwrap();
+        assert_eq!(resolved.counter, "a".into());
+        assert!(resolve(&registered, update(MetricRef::Id(2))).is_none());
+        assert!(resolve(&registered, update("b".into())).is_some());
+
+        let counters = RwLock::new(HashMap::new());
+        let sources = SourceSeqs::default();
+        assert_eq!(
+            apply_update(
+                &counters,
+                &sources,
+                &Retention::default(),
+                update(MetricRef::Id(2)),
+                10
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn batches_apply_once_across_metric_kinds() {
+        let metrics = Metrics::default();
+        let registered = HashMap::from([(1, CounterKey::new("a"))]);
+        let counter_entry = |counter: MetricRef, count| BatchEntry {
+            counter,
+            state: vec![CounterState {
+                epoch_seconds: 10 * MINUTE,
+                count,
+            }],
+        };
+        let batch = BatchMessage {
+            counters: vec![
+                counter_entry(MetricRef::Id(1), 1),
+                counter_entry("b".into(), 2),
+                // Never registered, so skipped
+                counter_entry(MetricRef::Id(9), 4),
+            ],
+            gauges: vec![BatchEntry {
+                counter: "depth".into(),
+                state: vec![GaugeState::new(10 * MINUTE, 3.0)],
+            }],
+            histograms: vec![],
+            source: 1,
+            seq: 1,
+        };
+
+        let mut first = batch.clone();
+        assert_eq!(resolve_batch(&registered, &mut first), 1);
+        assert_eq!(apply_batch(&metrics, first, 10 * MINUTE), Some(0));
+        // A resend of the same batch is discarded as a whole
+        let mut resend = batch;
+        resolve_batch(&registered, &mut resend);
+        assert_eq!(apply_batch(&metrics, resend, 10 * MINUTE), None);
+
+        assert_eq!(
+            read_series(&metrics.counters, &"a".into(), 10 * MINUTE),
+            vec![CounterState {
+                epoch_seconds: 10 * MINUTE,
+                count: 1
+            }]
+        );
+        assert_eq!(
+            read_series(&metrics.counters, &"b".into(), 10 * MINUTE)[0].count,
+            2
+        );
+        assert_eq!(metrics.counters.read().unwrap().len(), 2);
+        assert_eq!(
+            read_series(&metrics.gauges, &"depth".into(), 10 * MINUTE)[0].last,
+            3.0
+        );
+    }
+
+    #[test]
+    fn labeled_counters_read_and_aggregate() {
+        let counters = RwLock::new(HashMap::new());
+        let sources = SourceSeqs::default();
+        let update = |counter: CounterKey, epoch_seconds, count| CounterUpdateMessage {
+            counter: counter.into(),
+            state: vec![CounterState {
+                epoch_seconds,
+                count,
+            }],
+            source: 0,
+            seq: 0,
+        };
+        let requests = |route: &str, status: &str| {
+            CounterKey::new("requests")
+                .label("route", route)
+                .label("status", status)
+        };
+
+        apply_update(
+            &counters,
+            &sources,
+            &Retention::default(),
+            update(requests("/a", "200"), 10 * MINUTE, 1),
+            10 * MINUTE,
+        );
+        apply_update(
+            &counters,
+            &sources,
+            &Retention::default(),
+            update(requests("/a", "500"), 10 * MINUTE, 2),
+            10 * MINUTE,
+        );
+        apply_update(
+            &counters,
+            &sources,
+            &Retention::default(),
+            update(requests("/b", "200"), 11 * MINUTE, 4),
+            11 * MINUTE,
+        );
+        apply_update(
+            &counters,
+            &sources,
+            &Retention::default(),
+            update("requests".into(), 11 * MINUTE, 8),
+            11 * MINUTE,
+        );
+        apply_update(
+            &counters,
+            &sources,
+            &Retention::default(),
+            update("other".into(), 11 * MINUTE, 16),
+            11 * MINUTE,
+        );
+
+        let state = |epoch_seconds, count| CounterState {
+            epoch_seconds,
+            count,
+        };
+
+        assert_eq!(
+            read_series(&counters, &requests("/a", "500"), 11 * MINUTE),
+            vec![state(10 * MINUTE, 2)]
+        );
+        assert!(read_series(&counters, &requests("/c", "200"), 11 * MINUTE).is_empty());
+
+        // Across every label set, including the unlabeled one
+        assert_eq!(
+            aggregate_series(&counters, "requests", &Labels::new(), 11 * MINUTE),
+            vec![state(10 * MINUTE, 3), state(11 * MINUTE, 12)]
+        );
+        // Across routes
+        let ok = CounterKey::new("requests").label("status", "200").labels;
+        assert_eq!(
+            aggregate_series(&counters, "requests", &ok, 11 * MINUTE),
+            vec![state(10 * MINUTE, 1), state(11 * MINUTE, 4)]
+        );
+    }
+
+    #[test]
+    fn queries_are_answered_with_series_or_errors() {
+        let metrics = Metrics::default();
+        let update = |epoch_seconds, count| CounterUpdateMessage {
+            counter: CounterKey::new("requests").label("status", "200").into(),
+            state: vec![CounterState {
+                epoch_seconds,
+                count,
+            }],
+            source: 0,
+            seq: 0,
+        };
+        apply_update(
+            &metrics.counters,
+            &metrics.sources,
+            &metrics.retention,
+            update(10 * MINUTE, 1),
+            10 * MINUTE,
+        );
+        apply_update(
+            &metrics.counters,
+            &metrics.sources,
+            &metrics.retention,
+            update(11 * MINUTE, 2),
+            11 * MINUTE,
+        );
+
+        let read = |counter: CounterKey, range: Range<u64>| Query::Read {
+            counter,
+            from_seconds: range.start,
+            to_seconds: range.end,
+        };
+        let key = CounterKey::new("requests").label("status", "200");
+        let series = match answer(&metrics, read(key.clone(), 0..12 * MINUTE), 11 * MINUTE) {
+            QueryResult::Counters(series) => series,
+            other => unreachable!("expected counters, got {:?}", other),
+        };
+        let counts: Vec<u64> = series.states.iter().map(|state| state.count).collect();
+        assert_eq!(counts, vec![1, 2]);
+        // Oldest and coarsest first, ending with the last 6 hours at 1 minute resolution
+        assert_eq!(series.resolutions.len(), Retention::default().tiers().len());
+        assert_eq!(
+            series.resolutions.last(),
+            Some(&Resolution {
+                from_seconds: 0,
+                interval_seconds: MINUTE
+            })
+        );
+
+        // Only the intervals starting in the range are returned
+        match answer(
+            &metrics,
+            read(key.clone(), 11 * MINUTE..12 * MINUTE),
+            11 * MINUTE,
+        ) {
+            QueryResult::Counters(series) => {
+                let counts: Vec<u64> = series.states.iter().map(|state| state.count).collect();
+                assert_eq!(counts, vec![2]);
+            }
+            other => unreachable!("expected counters, got {:?}", other),
+        }
+        assert!(matches!(
+            answer(&metrics, read(key, 12 * MINUTE..12 * MINUTE), 11 * MINUTE),
+            QueryResult::Error(QueryError::InvalidQuery(_))
+        ));
+
+        assert_eq!(
+            answer(
+                &metrics,
+                read("requests".into(), 0..12 * MINUTE),
+                11 * MINUTE
+            ),
+            QueryResult::Error(QueryError::UnknownMetric("requests".into()))
+        );
+        assert!(matches!(
+            answer(
+                &metrics,
+                Query::Aggregate {
+                    name: "requests".into(),
+                    labels: Labels::new(),
+                    from_seconds: 0,
+                    to_seconds: 12 * MINUTE,
+                },
+                11 * MINUTE
+            ),
+            QueryResult::Counters(_)
+        ));
+        assert!(matches!(
+            answer(
+                &metrics,
+                Query::AggregateGauges {
+                    name: "requests".into(),
+                    labels: Labels::new(),
+                    from_seconds: 0,
+                    to_seconds: 12 * MINUTE,
+                },
+                11 * MINUTE
+            ),
+            QueryResult::Error(QueryError::NoMatchingMetrics { .. })
+        ));
+        let percentiles = |quantiles| Query::Percentiles {
+            name: "latency".into(),
+            labels: Labels::new(),
+            from_seconds: 0,
+            to_seconds: 12 * MINUTE,
+            quantiles,
+        };
+        assert!(matches!(
+            answer(&metrics, percentiles(vec![1.5]), 11 * MINUTE),
+            QueryResult::Error(QueryError::InvalidQuery(_))
+        ));
+        assert_eq!(
+            answer(&metrics, Query::List, 11 * MINUTE),
+            QueryResult::Metrics(MetricList {
+                counters: vec![CounterKey::new("requests").label("status", "200")],
+                ..MetricList::default()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn embedded_server_serves_until_shut_down() {
+        let server = CounterServer::builder()
+            .config(CounterConfig::builder().port(0).build())
+            .bind()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+        let handle = server.handle();
+        let serving = tokio::spawn(server.serve());
+
+        let mut updates = tokio_serde::SymmetricallyFramed::new(
+            FramedWrite::new(
+                TcpStream::connect(addr).await.unwrap(),
+                LengthDelimitedCodec::new(),
+            ),
+            SymmetricalJson::<CounterMessage>::default(),
+        );
+        let update = CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds: get_epoc_seconds(),
+                count: 3,
+            }],
+            source: 0,
+            seq: 0,
+        };
+        updates.send(CounterMessage::Update(update)).await.unwrap();
+
+        let config = CounterConfig::builder()
+            .host(addr.ip().to_string())
+            .port(addr.port())
+            .build();
+        let mut client = CounterClient::connect(&config).await.unwrap();
+        // The update arrives on another connection, so may not be applied yet
+        let series = loop {
+            match client.read("a", 0..u64::MAX).await {
+                Err(Error::Query(_)) => tokio::task::yield_now().await,
+                result => break result.unwrap(),
+            }
+        };
+        assert_eq!(series.states[0].count, 3);
+
+        // Closed by the clients, so the server needn't wait out the drain timeout
+        drop(updates);
+        drop(client);
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[test]
+    fn out_of_range_states_are_dropped_and_counted() {
+        const NOW: u64 = 200 * 24 * HOUR;
+        let counters = RwLock::new(HashMap::new());
+        let sources = SourceSeqs::default();
+        let state = |epoch_seconds| CounterState {
+            epoch_seconds,
+            count: 1,
+        };
+        let update = CounterUpdateMessage {
+            counter: "a".into(),
+            // Past the 90 days kept, a minute ahead, and far ahead of the server clock
+            state: vec![state(0), state(NOW + MINUTE), state(NOW + HOUR)],
+            source: 0,
+            seq: 0,
+        };
+
+        assert_eq!(
+            apply_update(&counters, &sources, &Retention::default(), update, NOW),
+            Some(2)
+        );
+        assert_eq!(
+            read_series(&counters, &"a".into(), NOW),
+            vec![state(NOW + MINUTE)]
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_frames_are_skipped_and_counted() {
+        let server = CounterServer::builder()
+            .config(CounterConfig::builder().port(0).build())
+            .bind()
+            .await
+            .unwrap();
+        let handle = server.handle();
+        tokio::spawn(server.serve());
+
+        let mut frames = FramedWrite::new(
+            TcpStream::connect(handle.local_addr()).await.unwrap(),
+            LengthDelimitedCodec::new(),
+        );
+        let update = |counter: MetricRef| {
+            let update = CounterUpdateMessage {
+                counter,
+                state: vec![CounterState {
+                    epoch_seconds: get_epoc_seconds(),
+                    count: 1,
+                }],
+                source: 0,
+                seq: 0,
+            };
+            serde_json::to_vec(&CounterMessage::Update(update)).unwrap()
+        };
+        frames.send(Bytes::from("not json")).await.unwrap();
+        frames
+            .send(Bytes::from(update(MetricRef::Id(7))))
+            .await
+            .unwrap();
+        // The connection stays open for the next message
+        frames.send(Bytes::from(update("a".into()))).await.unwrap();
+
+        let applied = || !handle.metrics.counters.read().unwrap().is_empty();
+        while handle.rejected().unregistered == 0 || !applied() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            handle.rejected(),
+            Rejected {
+                malformed: 1,
+                unregistered: 1,
+                out_of_range: 0,
+            }
+        );
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_open_connections() {
+        let server = CounterServer::builder()
+            .config(CounterConfig::builder().port(0).build())
+            .drain_timeout(Duration::from_secs(10))
+            .bind()
+            .await
+            .unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(server.serve());
+
+        let mut updates = tokio_serde::SymmetricallyFramed::new(
+            FramedWrite::new(
+                TcpStream::connect(handle.local_addr()).await.unwrap(),
+                LengthDelimitedCodec::new(),
+            ),
+            SymmetricalJson::<CounterMessage>::default(),
+        );
+        let mut idle = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let update = CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds: get_epoc_seconds(),
+                count: 1,
+            }],
+            source: 0,
+            seq: 0,
+        };
+        updates.send(CounterMessage::Update(update)).await.unwrap();
+        while handle.metrics.counters.read().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        handle.shutdown();
+
+        // Open connections close without waiting out the drain timeout
+        let start = tokio::time::Instant::now();
+        serving.await.unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(TcpStream::connect(handle.local_addr()).await.is_err());
+        let mut buf = [0; 1];
+        assert_eq!(
+            tokio::io::AsyncReadExt::read(&mut idle, &mut buf)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn restored_series_are_shifted_to_now() {
+        const START_POINT: u64 = 18 * HOUR;
+        let update = || CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds: START_POINT,
+                count: 2,
+            }],
+            source: 1,
+            seq: 4,
+        };
+        let metrics = Metrics::default();
+        apply_update(
+            &metrics.counters,
+            &metrics.sources,
+            &metrics.retention,
+            update(),
+            START_POINT,
+        );
+
+        // Through JSON, as on disk
+        let snapshot = serde_json::to_vec(&metrics.snapshot(START_POINT)).unwrap();
+        let snapshot = serde_json::from_slice(&snapshot).unwrap();
+        let restored = Metrics::restore(
+            snapshot,
+            Retention::default(),
+            START_POINT + 6 * HOUR + MINUTE,
+        );
+
+        let series = restored.counters.read().unwrap()[&CounterKey::from("a")].clone();
+        {
+            let series = series.lock().unwrap();
+            assert!(series[0].data.is_empty());
+            assert_eq!(
+                series[1].data,
+                vec![CounterState {
+                    epoch_seconds: START_POINT,
+                    count: 2
+                }]
+            );
+        }
+        // A resend from before the restart is still discarded
+        assert_eq!(
+            apply_update(
+                &restored.counters,
+                &restored.sources,
+                &Retention::default(),
+                update(),
+                START_POINT + 6 * HOUR + MINUTE
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_reloaded_after_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "async-pub-server-snapshot-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let bind = || {
+            CounterServer::builder()
+                .config(CounterConfig::builder().port(0).build())
+                .snapshot_path(&path)
+                .snapshot_interval(Duration::from_millis(10))
+                .bind()
+        };
+
+        let server = bind().await.unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(server.serve());
+        let mut updates = tokio_serde::SymmetricallyFramed::new(
+            FramedWrite::new(
+                TcpStream::connect(handle.local_addr()).await.unwrap(),
+                LengthDelimitedCodec::new(),
+            ),
+            SymmetricalJson::<CounterMessage>::default(),
+        );
+        let now = get_epoc_seconds();
+        let update = CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds: now,
+                count: 5,
+            }],
+            source: 0,
+            seq: 0,
+        };
+        updates.send(CounterMessage::Update(update)).await.unwrap();
+        while handle.metrics.counters.read().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        // Written periodically, before any shutdown
+        while !path.exists() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        drop(updates);
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+
+        let restarted = bind().await.unwrap();
+        let states = read_series(&restarted.metrics.counters, &"a".into(), now);
+        assert_eq!(states.iter().map(|state| state.count).sum::<u64>(), 5);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn logged_updates_are_replayed_after_a_crash() {
+        let path = std::env::temp_dir().join(format!(
+            "async-pub-server-wal-snapshot-{}.json",
+            std::process::id()
+        ));
+        let dir = std::env::temp_dir().join(format!("async-pub-server-wal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_dir_all(&dir);
+        let unsnapshotted = CounterServer::builder()
+            .config(CounterConfig::builder().port(0).build())
+            .wal_dir(&dir)
+            .bind()
+            .await;
+        assert!(matches!(unsnapshotted, Err(Error::Config(_))));
+        let bind = || {
+            CounterServer::builder()
+                .config(CounterConfig::builder().port(0).build())
+                .snapshot_path(&path)
+                .wal_dir(&dir)
+                .wal_fsync(FsyncPolicy::Always)
+                .bind()
+        };
+
+        let server = bind().await.unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(server.serve());
+        let mut updates = tokio_serde::SymmetricallyFramed::new(
+            FramedWrite::new(
+                TcpStream::connect(handle.local_addr()).await.unwrap(),
+                LengthDelimitedCodec::new(),
+            ),
+            SymmetricalJson::<CounterMessage>::default(),
+        );
+        let now = get_epoc_seconds();
+        let update = CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds: now,
+                count: 5,
+            }],
+            source: 0,
+            seq: 0,
+        };
+        updates.send(CounterMessage::Update(update)).await.unwrap();
+        while handle.metrics.counters.read().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        // Crash, without a final snapshot
+        serving.abort();
+        let _ = serving.await;
+        assert!(!path.exists());
+
+        let count = |server: &CounterServer| {
+            let states = read_series(&server.metrics.counters, &"a".into(), now);
+            states.iter().map(|state| state.count).sum::<u64>()
+        };
+        let replayed = bind().await.unwrap();
+        assert_eq!(count(&replayed), 5);
+
+        // The snapshot at shutdown covers the logged update, so it isn't replayed again
+        let handle = replayed.handle();
+        let serving = tokio::spawn(replayed.serve());
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+        let restarted = bind().await.unwrap();
+        assert_eq!(count(&restarted), 5);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn updates_the_log_fails_to_record_are_applied_and_counted() {
+        let dir =
+            std::env::temp_dir().join(format!("async-pub-server-wal-full-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        wal.fail_writes();
+        let handle = CounterServerHandle {
+            local_addr: ([127, 0, 0, 1], 0).into(),
+            metrics: Arc::new(Metrics {
+                wal: Some(Mutex::new(wal)),
+                ..Metrics::default()
+            }),
+            shutdown: CancellationToken::new(),
+        };
+
+        let update = CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds: 10,
+                count: 1,
+            }],
+            source: 0,
+            seq: 0,
+        };
+        assert_eq!(
+            handle.metrics.apply(CounterMessage::Update(update), 10),
+            Some(0)
+        );
+        assert_eq!(handle.unlogged_updates(), 1);
+        assert_eq!(
+            read_series(&handle.metrics.counters, &"a".into(), 10)[0].count,
+            1
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn series_use_the_configured_retention() {
+        let retention = Retention::new(vec![
+            RetentionTier {
+                interval_seconds: 1,
+                interval_count: 10,
+            },
+            RetentionTier {
+                interval_seconds: 10,
+                interval_count: 6,
+            },
+        ])
+        .unwrap();
+        let metrics = Metrics {
+            retention,
+            ..Metrics::default()
+        };
+        let update = |epoch_seconds| CounterUpdateMessage {
+            counter: "a".into(),
+            state: vec![CounterState {
+                epoch_seconds,
+                count: 1,
+            }],
+            source: 0,
+            seq: 0,
+        };
+
+        const NOW: u64 = 1000;
+        for epoch_seconds in [NOW - 35, NOW - 32, NOW - 5] {
+            let applied = metrics.apply(CounterMessage::Update(update(epoch_seconds)), NOW);
+            assert_eq!(applied, Some(0));
+        }
+        // Past the 70 seconds the tiers keep
+        let applied = metrics.apply(CounterMessage::Update(update(NOW - 80)), NOW);
+        assert_eq!(applied, Some(1));
+
+        let query = Query::Read {
+            counter: "a".into(),
+            from_seconds: 0,
+            to_seconds: NOW,
+        };
+        let series = match answer(&metrics, query, NOW) {
+            QueryResult::Counters(series) => series,
+            other => unreachable!("expected counters, got {:?}", other),
+        };
+        let states: Vec<(u64, u64)> = series
+            .states
+            .iter()
+            .map(|state| (state.epoch_seconds, state.count))
+            .collect();
+        assert_eq!(states, vec![(NOW - 40, 2), (NOW - 5, 1)]);
+        assert_eq!(
+            series.resolutions,
+            vec![
+                Resolution {
+                    from_seconds: NOW - 70,
+                    interval_seconds: 10,
+                },
+                Resolution {
+                    from_seconds: NOW - 10,
+                    interval_seconds: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn continuously_updated_series_graduate_and_expire() {
+        let retention = Retention::new(vec![
+            RetentionTier {
+                interval_seconds: MINUTE,
+                interval_count: 10,
+            },
+            RetentionTier {
+                interval_seconds: 10 * MINUTE,
+                interval_count: 6,
+            },
+        ])
+        .unwrap();
+        let mut series = create_time_series(&retention);
+        for minute in 0..30 {
+            let state = CounterState {
+                epoch_seconds: minute * MINUTE,
+                count: 1,
+            };
+            assert_eq!(
+                update_time_series(&mut series, vec![state], minute * MINUTE),
+                0
+            );
+        }
+
+        // The last 10 minutes, through minute 19, stay at 1 minute resolution
+        let minutes: Vec<u64> = series[0]
+            .data
+            .iter()
+            .map(|state| state.epoch_seconds / MINUTE)
+            .collect();
+        assert_eq!(minutes, (19..30).rev().collect::<Vec<u64>>());
+        let older: Vec<(u64, u64)> = series[1]
+            .data
+            .iter()
+            .map(|state| (state.epoch_seconds / MINUTE, state.count))
+            .collect();
+        assert_eq!(older, vec![(10, 9), (0, 10)]);
+
+        // Past both tiers' 70 minutes
+        shift_time_series(&mut series, 100 * MINUTE);
+        assert!(series.iter().all(|bucket| bucket.data.is_empty()));
+    }
+
+    #[test]
+    fn gauge_intervals_merge_when_graduating() {
+        const START_POINT: u64 = 18 * HOUR;
+
+        let mut series = create_time_series(&Retention::default());
+        let mut first = GaugeState::new(START_POINT, 5.0);
+        first.record(1.0);
+        add_to_series(&mut series, first, START_POINT);
+        add_to_series(
+            &mut series,
+            GaugeState::new(START_POINT + MINUTE, 3.0),
+            START_POINT + MINUTE,
+        );
+        assert_eq!(series[0].data.len(), 2);
+
+        // Both minutes fall into one 5 minute interval
+        shift_time_series(&mut series, START_POINT + 6 * HOUR + 2 * MINUTE);
+
+        assert!(series[0].data.is_empty());
+        assert_eq!(
+            series[1].data,
+            vec![GaugeState {
+                epoch_seconds: START_POINT,
+                last: 3.0,
+                min: 1.0,
+                max: 5.0,
+                sum: 9.0,
+                count: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn percentiles_over_a_range() {
+        let histograms = RwLock::new(HashMap::new());
+        let sources = SourceSeqs::default();
+        let update = |route: &str, epoch_seconds, values: &[f64]| {
+            let mut state = HistogramState::new(epoch_seconds, values[0]);
+            values[1..].iter().for_each(|value| state.record(*value));
+            UpdateMessage {
+                counter: CounterKey::new("latency").label("route", route).into(),
+                state: vec![state],
+                source: 0,
+                seq: 0,
+            }
+        };
+        let fast: Vec<f64> = (1..=90).map(|value| value as f64).collect();
+        let slow: Vec<f64> = (991..=1000).map(|value| value as f64).collect();
+
+        apply_update(
+            &histograms,
+            &sources,
+            &Retention::default(),
+            update("/a", 10 * MINUTE, &fast),
+            12 * MINUTE,
+        );
+        apply_update(
+            &histograms,
+            &sources,
+            &Retention::default(),
+            update("/b", 11 * MINUTE, &slow),
+            12 * MINUTE,
+        );
+        apply_update(
+            &histograms,
+            &sources,
+            &Retention::default(),
+            update("/b", 12 * MINUTE, &[5000.0]),
+            12 * MINUTE,
+        );
+
+        let values = percentiles(
+            &histograms,
+            "latency",
+            &Labels::new(),
+            10 * MINUTE..12 * MINUTE,
+            &[0.5, 0.99],
+            12 * MINUTE,
+        );
+        let p50 = values[0].unwrap();
+        assert!((p50 - 50.0).abs() / 50.0 < 0.03, "{}", p50);
+        let p99 = values[1].unwrap();
+        assert!((p99 - 990.0).abs() / 990.0 < 0.03, "{}", p99);
+
+        let one_route = CounterKey::new("").label("route", "/b").labels;
+        let values = percentiles(
+            &histograms,
+            "latency",
+            &one_route,
+            12 * MINUTE..13 * MINUTE,
+            &[0.5],
+            12 * MINUTE,
+        );
+        assert_eq!(values, vec![Some(5000.0)]);
+
+        assert_eq!(
+            percentiles(
+                &histograms,
+                "latency",
+                &Labels::new(),
+                0..5 * MINUTE,
+                &[0.5],
+                12 * MINUTE
+            ),
+            vec![None]
+        );
+    }
+
     #[test]
     fn shift_time_series_no_shift() {
-        const START_POINT: u64 = 18 * 60;
+        const START_POINT: u64 = 18 * HOUR;
 
-        let mut series = create_time_series();
+        let mut series = create_time_series(&Retention::default());
         let counter_state = CounterState {
-            epoch_minutes: 10,
+            epoch_seconds: 10 * MINUTE,
             count: 2,
         };
 
         add_to_series(&mut series, counter_state, START_POINT);
 
         let counter_state = CounterState {
-            epoch_minutes: 11,
+            epoch_seconds: 11 * MINUTE,
             count: 3,
         };
 
-        add_to_series(&mut series, counter_state, START_POINT + 1);
+        add_to_series(&mut series, counter_state, START_POINT + MINUTE);
 
         let pre_shift = series.clone();
 
@@ -558,58 +2593,55 @@ mod tests {
 
     #[test]
     fn shift_time_series_shift_multiple_to_one() {
-        const START_POINT: u64 = 18 * 60;
+        const START_POINT: u64 = 18 * HOUR;
 
-        let mut series = create_time_series();
+        let mut series = create_time_series(&Retention::default());
         let counter_state = CounterState {
-            epoch_minutes: 10,
+            epoch_seconds: 10 * MINUTE,
             count: 2,
         };
 
         add_to_series(&mut series, counter_state, START_POINT);
 
         let counter_state = CounterState {
-            epoch_minutes: 11,
+            epoch_seconds: 11 * MINUTE,
             count: 3,
         };
 
-        add_to_series(&mut series, counter_state, START_POINT + 1);
+        add_to_series(&mut series, counter_state, START_POINT + MINUTE);
 
         assert_eq!(series[0].data.len(), 0);
         assert_eq!(series[1].data.len(), 1);
-        assert_eq!(
-            series[2..].iter().all(|bucket| bucket.data.is_empty()),
-            true
-        );
+        assert!(series[2..].iter().all(|bucket| bucket.data.is_empty()));
 
         let pre_shift = series.clone();
 
         // One day later
-        shift_time_series(&mut series, START_POINT + (60 * 24));
+        shift_time_series(&mut series, START_POINT + 24 * HOUR);
 
         assert_ne!(series, pre_shift);
         assert_eq!(series[0].data.len(), 0);
         assert_eq!(series[1].data.len(), 0);
         assert_eq!(series[2].data.len(), 1);
-        assert_eq!(series[2].data[0].epoch_minutes, 0);
+        assert_eq!(series[2].data[0].epoch_seconds, 0);
         assert_eq!(series[2].data[0].count, 5);
         assert!(series[3..].iter().all(|bucket| bucket.data.is_empty()));
     }
 
     #[test]
     fn shift_time_series_shift_multiple_to_multiple() {
-        const START_POINT: u64 = 18 * 60;
+        const START_POINT: u64 = 18 * HOUR;
 
-        let mut series = create_time_series();
+        let mut series = create_time_series(&Retention::default());
         let counter_state = CounterState {
-            epoch_minutes: 10,
+            epoch_seconds: 10 * MINUTE,
             count: 2,
         };
 
         add_to_series(&mut series, counter_state, START_POINT);
 
         let counter_state = CounterState {
-            epoch_minutes: START_POINT - 10,
+            epoch_seconds: START_POINT - 10 * MINUTE,
             count: 3,
         };
 
@@ -617,21 +2649,21 @@ mod tests {
 
         assert_eq!(series[0].data.len(), 1);
         assert_eq!(series[1].data.len(), 1);
-        assert_eq!(series[2..].iter().all(|bucket| bucket.data.is_empty()), true);
+        assert!(series[2..].iter().all(|bucket| bucket.data.is_empty()));
 
         let pre_shift = series.clone();
 
         // 18 hours later
-        shift_time_series(&mut series, START_POINT + (60 * 18));
+        shift_time_series(&mut series, START_POINT + 18 * HOUR);
 
         assert_ne!(series, pre_shift);
         assert_eq!(series[0].data.len(), 0);
         assert_eq!(series[1].data.len(), 1);
-        assert_eq!(series[1].data[0].epoch_minutes, 1070);
+        assert_eq!(series[1].data[0].epoch_seconds, 1070 * MINUTE);
         assert_eq!(series[1].data[0].count, 3);
         assert_eq!(series[2].data.len(), 1);
-        assert_eq!(series[2].data[0].epoch_minutes, 0);
+        assert_eq!(series[2].data[0].epoch_seconds, 0);
         assert_eq!(series[2].data[0].count, 2);
-        assert_eq!(series[3..].iter().all(|bucket| bucket.data.is_empty()), true);
+        assert!(series[3..].iter().all(|bucket| bucket.data.is_empty()));
     }
 }
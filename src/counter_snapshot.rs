@@ -0,0 +1,143 @@
+// The counter server's series on disk, so a restart keeps its retention.
+//
+// A snapshot is one JSON file, replaced atomically: it is written to a temporary file
+// beside the target, synced, then renamed over it. A crash mid write leaves the previous
+// snapshot in place.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::counter_types::{CounterKey, CounterState, GaugeState, HistogramState};
+use crate::error::Error;
+
+/// Bumped on any change to the format that older servers can't read
+/// 2 keeps times in epoch seconds rather than minutes.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Snapshot {
+    pub version: u32,
+    /// Server time the snapshot was taken at
+    pub epoch_seconds: u64,
+    pub counters: Vec<SeriesSnapshot<CounterState>>,
+    pub gauges: Vec<SeriesSnapshot<GaugeState>>,
+    pub histograms: Vec<SeriesSnapshot<HistogramState>>,
+    /// Last applied sequence number for each update source, so resends stay discarded
+    pub sources: HashMap<u64, u64>,
+    /// Last write-ahead log record the snapshot includes. Replay starts after it.
+    #[serde(default)]
+    pub wal_lsn: u64,
+}
+
+/// One series' states, oldest to newest, independent of the bucket layout
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SeriesSnapshot<S> {
+    pub key: CounterKey,
+    pub states: Vec<S>,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+/// Replace the snapshot at `path`
+pub fn write(path: &Path, snapshot: &Snapshot) -> Result<(), Error> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&serde_json::to_vec(snapshot)?)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    // Make the rename itself durable
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The snapshot at `path`, or None if there isn't one yet
+pub fn read(path: &Path) -> Result<Option<Snapshot>, Error> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid = |e: serde_json::Error| Error::Config(format!("{}: {}", path.display(), e));
+
+    let Version { version } = serde_json::from_slice(&contents).map_err(invalid)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(Error::Config(format!(
+            "{}: snapshot version {} is not supported, expected {}",
+            path.display(),
+            version,
+            SNAPSHOT_VERSION
+        )));
+    }
+    Ok(Some(serde_json::from_slice(&contents).map_err(invalid)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    fn temp_snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "async-pub-{}-{}-{}.json",
+            name,
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ))
+    }
+
+    #[test]
+    fn snapshots_round_trip_and_replace() {
+        let path = temp_snapshot_path("snapshot");
+        assert_eq!(read(&path).unwrap(), None);
+
+        let mut snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            epoch_seconds: 10,
+            counters: vec![SeriesSnapshot {
+                key: CounterKey::new("requests").label("status", "200"),
+                states: vec![CounterState {
+                    epoch_seconds: 9,
+                    count: 3,
+                }],
+            }],
+            sources: HashMap::from([(7, 2)]),
+            ..Snapshot::default()
+        };
+        write(&path, &snapshot).unwrap();
+        snapshot.epoch_seconds = 11;
+        write(&path, &snapshot).unwrap();
+
+        assert_eq!(read(&path).unwrap(), Some(snapshot));
+        let mut temp_name = path.file_name().unwrap().to_owned();
+        temp_name.push(".tmp");
+        assert!(!path.with_file_name(temp_name).exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let path = temp_snapshot_path("snapshot-version");
+        fs::write(&path, r#"{"version": 99, "future_field": []}"#).unwrap();
+
+        assert!(matches!(read(&path), Err(Error::Config(_))));
+        fs::remove_file(path).unwrap();
+    }
+}
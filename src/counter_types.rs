@@ -1,30 +1,678 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::time;
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(from = "CounterStateRepr")]
 pub struct CounterState {
-    pub epoch_minutes: u64,
-    pub count: usize,
+    /// Start of the interval
+    pub epoch_seconds: u64,
+    pub count: u64,
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct CounterUpdateMessage {
-    pub counter: String,
-    pub state: Vec<CounterState>,
+/// Clients from before second resolution send the minute instead
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CounterStateRepr {
+    Seconds { epoch_seconds: u64, count: u64 },
+    Minutes { epoch_minutes: u64, count: u64 },
+}
+
+impl From<CounterStateRepr> for CounterState {
+    fn from(repr: CounterStateRepr) -> Self {
+        match repr {
+            CounterStateRepr::Seconds {
+                epoch_seconds,
+                count,
+            } => CounterState {
+                epoch_seconds,
+                count,
+            },
+            CounterStateRepr::Minutes {
+                epoch_minutes,
+                count,
+            } => CounterState {
+                epoch_seconds: epoch_minutes.saturating_mul(60),
+                count,
+            },
+        }
+    }
+}
+
+/// A gauge's samples over one interval
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct GaugeState {
+    /// Start of the interval
+    pub epoch_seconds: u64,
+    /// The most recent sample
+    pub last: f64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    /// Number of samples
+    pub count: u64,
+}
+
+impl GaugeState {
+    pub fn new(epoch_seconds: u64, value: f64) -> GaugeState {
+        GaugeState {
+            epoch_seconds,
+            last: value,
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    pub fn record(&mut self, value: f64) {
+        self.merge(GaugeState::new(self.epoch_seconds, value));
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Histogram buckets grow by this factor, bounding the relative error of a percentile to ~2%
+const HISTOGRAM_GAMMA: f64 = 1.04;
+
+/// A histogram's samples over one interval, in fixed log scale buckets.
+/// Any two histograms can be merged by adding their buckets.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct HistogramState {
+    /// Start of the interval
+    pub epoch_seconds: u64,
+    /// Sample counts by bucket index. Bucket `i` holds values in (gamma^(i-1), gamma^i].
+    pub buckets: BTreeMap<i32, u64>,
+    /// Samples that were zero or negative. Quantiles among them report the smallest sample.
+    pub zero_count: u64,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl HistogramState {
+    pub fn new(epoch_seconds: u64, value: f64) -> HistogramState {
+        let mut state = HistogramState {
+            epoch_seconds,
+            buckets: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        };
+        state.record(value);
+        state
+    }
+
+    /// Add a sample. NaN and infinite samples are ignored, since they'd poison the sum.
+    pub fn record(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        if value > 0.0 {
+            let index = value.ln() / HISTOGRAM_GAMMA.ln();
+            *self.buckets.entry(index.ceil() as i32).or_insert(0) += 1;
+        } else {
+            self.zero_count += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// The value at quantile `q` in [0, 1], ie. 0.99 for p99. None if there are no samples.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+        if rank < self.zero_count {
+            return Some(self.min.min(0.0));
+        }
+        let mut seen = self.zero_count;
+        for (index, count) in self.buckets.iter() {
+            seen += count;
+            if rank < seen {
+                // The middle of the bucket, by relative error
+                let value = 2.0 * HISTOGRAM_GAMMA.powi(*index) / (HISTOGRAM_GAMMA + 1.0);
+                return Some(value.clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
 }
 
+/// The value of a metric over one interval.
+/// Intervals are merged as updates arrive and as they graduate into coarser buckets.
+pub trait IntervalState: Clone {
+    fn epoch_seconds(&self) -> u64;
+
+    fn set_epoch_seconds(&mut self, epoch_seconds: u64);
+
+    /// Fold in a state for the same interval recorded at the same time or later
+    fn merge(&mut self, later: Self);
+
+    /// Fold in the same interval of another series, when aggregating across labels
+    fn combine(&mut self, other: Self) {
+        self.merge(other);
+    }
+}
+
+impl IntervalState for CounterState {
+    fn epoch_seconds(&self) -> u64 {
+        self.epoch_seconds
+    }
+
+    fn set_epoch_seconds(&mut self, epoch_seconds: u64) {
+        self.epoch_seconds = epoch_seconds;
+    }
+
+    fn merge(&mut self, later: Self) {
+        self.count = self.count.saturating_add(later.count);
+    }
+}
+
+impl IntervalState for GaugeState {
+    fn epoch_seconds(&self) -> u64 {
+        self.epoch_seconds
+    }
+
+    fn set_epoch_seconds(&mut self, epoch_seconds: u64) {
+        self.epoch_seconds = epoch_seconds;
+    }
+
+    fn merge(&mut self, later: Self) {
+        self.last = later.last;
+        self.min = self.min.min(later.min);
+        self.max = self.max.max(later.max);
+        self.sum += later.sum;
+        self.count += later.count;
+    }
+
+    /// Across label sets the last values are summed, ie. total queue depth over every host
+    fn combine(&mut self, other: Self) {
+        let last = self.last + other.last;
+        self.merge(other);
+        self.last = last;
+    }
+}
+
+impl IntervalState for HistogramState {
+    fn epoch_seconds(&self) -> u64 {
+        self.epoch_seconds
+    }
+
+    fn set_epoch_seconds(&mut self, epoch_seconds: u64) {
+        self.epoch_seconds = epoch_seconds;
+    }
+
+    fn merge(&mut self, later: Self) {
+        for (index, count) in later.buckets {
+            *self.buckets.entry(index).or_insert(0) += count;
+        }
+        self.zero_count += later.zero_count;
+        self.count += later.count;
+        self.sum += later.sum;
+        self.min = self.min.min(later.min);
+        self.max = self.max.max(later.max);
+    }
+}
+
+pub type Labels = BTreeMap<String, String>;
+
+/// A counter name plus key/value labels, ie. route and status code.
+/// Each distinct label set is its own series on the server.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(from = "CounterKeyRepr", into = "CounterKeyRepr")]
+pub struct CounterKey {
+    pub name: String,
+    pub labels: Labels,
+}
+
+impl CounterKey {
+    pub fn new(name: impl Into<String>) -> CounterKey {
+        CounterKey {
+            name: name.into(),
+            labels: Labels::new(),
+        }
+    }
+
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> CounterKey {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Whether this key has the name and every one of the labels
+    pub fn matches(&self, name: &str, labels: &Labels) -> bool {
+        self.name == name
+            && labels
+                .iter()
+                .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+}
+
+impl From<String> for CounterKey {
+    fn from(name: String) -> Self {
+        CounterKey::new(name)
+    }
+}
+
+impl From<&str> for CounterKey {
+    fn from(name: &str) -> Self {
+        CounterKey::new(name)
+    }
+}
+
+/// Unlabeled counters are sent as just the name, as before labels existed
 #[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum CounterKeyRepr {
+    Name(String),
+    Labeled { name: String, labels: Labels },
+}
+
+impl From<CounterKeyRepr> for CounterKey {
+    fn from(repr: CounterKeyRepr) -> Self {
+        match repr {
+            CounterKeyRepr::Name(name) => CounterKey::new(name),
+            CounterKeyRepr::Labeled { name, labels } => CounterKey { name, labels },
+        }
+    }
+}
+
+impl From<CounterKey> for CounterKeyRepr {
+    fn from(key: CounterKey) -> Self {
+        if key.labels.is_empty() {
+            CounterKeyRepr::Name(key.name)
+        } else {
+            CounterKeyRepr::Labeled {
+                name: key.name,
+                labels: key.labels,
+            }
+        }
+    }
+}
+
+/// How an update names its metric: by key, or by an id registered earlier on the same connection
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum MetricRef {
+    Id(u32),
+    Key(CounterKey),
+}
+
+impl MetricRef {
+    /// The key, if this isn't an id still to be resolved
+    pub fn key(&self) -> Option<&CounterKey> {
+        match self {
+            MetricRef::Id(_) => None,
+            MetricRef::Key(key) => Some(key),
+        }
+    }
+}
+
+impl From<CounterKey> for MetricRef {
+    fn from(key: CounterKey) -> Self {
+        MetricRef::Key(key)
+    }
+}
+
+impl From<&str> for MetricRef {
+    fn from(name: &str) -> Self {
+        MetricRef::Key(name.into())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UpdateMessage<S> {
+    /// The counter or other metric
+    pub counter: MetricRef,
+    /// What was recorded in each interval since the last update from this source
+    pub state: Vec<S>,
+    /// Random id of the sending process
+    #[serde(default)]
+    pub source: u64,
+    /// Increases with each update a source sends. The server applies an update only if its
+    /// sequence number is newer than the last it saw from the source, so resends are harmless.
+    /// 0 disables the check.
+    #[serde(default)]
+    pub seq: u64,
+}
+
+/// One metric's states within a batch
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BatchEntry<S> {
+    pub counter: MetricRef,
+    pub state: Vec<S>,
+}
+
+/// Updates for many metrics in one frame.
+/// The sequence number covers the whole batch, so it is applied or discarded as a unit.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BatchMessage {
+    #[serde(default)]
+    pub counters: Vec<BatchEntry<CounterState>>,
+    #[serde(default)]
+    pub gauges: Vec<BatchEntry<GaugeState>>,
+    #[serde(default)]
+    pub histograms: Vec<BatchEntry<HistogramState>>,
+    #[serde(default)]
+    pub source: u64,
+    #[serde(default)]
+    pub seq: u64,
+}
+
+impl BatchMessage {
+    /// Number of states across every entry
+    pub fn len(&self) -> usize {
+        self.counters
+            .iter()
+            .map(|entry| entry.state.len())
+            .sum::<usize>()
+            + self
+                .gauges
+                .iter()
+                .map(|entry| entry.state.len())
+                .sum::<usize>()
+            + self
+                .histograms
+                .iter()
+                .map(|entry| entry.state.len())
+                .sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.gauges.is_empty() && self.histograms.is_empty()
+    }
+}
+
+pub type CounterUpdateMessage = UpdateMessage<CounterState>;
+pub type GaugeUpdateMessage = UpdateMessage<GaugeState>;
+pub type HistogramUpdateMessage = UpdateMessage<HistogramState>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum CounterMessage {
-    Read(String),
+    /// Names `id` for the rest of the connection, so updates can send the id instead of the key
+    Register {
+        id: u32,
+        counter: CounterKey,
+    },
+    /// The server answers with a `CounterResponse` carrying the same id
+    Query {
+        id: u64,
+        query: Query,
+    },
     Update(CounterUpdateMessage),
+    UpdateGauge(GaugeUpdateMessage),
+    UpdateHistogram(HistogramUpdateMessage),
+    Batch(BatchMessage),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Query {
+    /// A single series, by its exact label set, over the intervals starting in
+    /// [from_seconds, to_seconds). The other reads take the same range.
+    Read {
+        counter: CounterKey,
+        from_seconds: u64,
+        to_seconds: u64,
+    },
+    /// The sum of every series with the name and at least these labels.
+    /// Leaving a label out aggregates across all of its values.
+    Aggregate {
+        name: String,
+        labels: Labels,
+        from_seconds: u64,
+        to_seconds: u64,
+    },
+    ReadGauge {
+        gauge: CounterKey,
+        from_seconds: u64,
+        to_seconds: u64,
+    },
+    /// Gauges combined across label sets, with the min and max over all of them
+    /// and the sum of their last values
+    AggregateGauges {
+        name: String,
+        labels: Labels,
+        from_seconds: u64,
+        to_seconds: u64,
+    },
+    ReadHistogram {
+        histogram: CounterKey,
+        from_seconds: u64,
+        to_seconds: u64,
+    },
+    AggregateHistograms {
+        name: String,
+        labels: Labels,
+        from_seconds: u64,
+        to_seconds: u64,
+    },
+    /// Quantiles, ie. 0.5, 0.9 and 0.99, of every histogram with the name and at least
+    /// these labels, over the intervals starting in [from_seconds, to_seconds)
+    Percentiles {
+        name: String,
+        labels: Labels,
+        from_seconds: u64,
+        to_seconds: u64,
+        quantiles: Vec<f64>,
+    },
+    /// The key of every series the server holds
+    List,
+}
+
+/// The answer to `CounterMessage::Query` with the same id
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CounterResponse {
+    pub id: u64,
+    pub result: QueryResult,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum QueryResult {
+    Counters(Series<CounterState>),
+    Gauges(Series<GaugeState>),
+    Histograms(Series<HistogramState>),
+    /// One value per requested quantile, None if nothing was recorded in the range
+    Percentiles(Vec<Option<f64>>),
+    Metrics(MetricList),
+    Error(QueryError),
+}
+
+/// Every series the server holds, by kind, each sorted by key
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MetricList {
+    pub counters: Vec<CounterKey>,
+    pub gauges: Vec<CounterKey>,
+    pub histograms: Vec<CounterKey>,
 }
 
-pub fn get_epoc_minutes() -> u64 {
-    let epoch_seconds = time::SystemTime::now()
+/// A metric's states, oldest to newest, with the resolution they were kept at
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Series<S> {
+    pub states: Vec<S>,
+    /// Oldest to newest
+    pub resolutions: Vec<Resolution>,
+}
+
+/// States starting at or after `from_seconds`, until the next resolution,
+/// each cover `interval_seconds`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Resolution {
+    pub from_seconds: u64,
+    pub interval_seconds: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum QueryError {
+    /// Nothing was ever recorded for the key
+    UnknownMetric(CounterKey),
+    /// No label set of the name has at least these labels
+    NoMatchingMetrics {
+        name: String,
+        labels: Labels,
+    },
+    InvalidQuery(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::UnknownMetric(key) => write!(f, "unknown metric {:?}", key),
+            QueryError::NoMatchingMetrics { name, labels } => {
+                write!(f, "no {} metrics match {:?}", name, labels)
+            }
+            QueryError::InvalidQuery(reason) => write!(f, "invalid query: {}", reason),
+        }
+    }
+}
+
+pub fn get_epoc_seconds() -> u64 {
+    time::SystemTime::now()
         .duration_since(time::UNIX_EPOCH)
         .expect("Time went backwards")
-        .as_secs();
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlabeled_key_is_a_plain_name_on_the_wire() {
+        let key = CounterKey::new("requests");
+        assert_eq!(serde_json::to_string(&key).unwrap(), r#""requests""#);
+        assert_eq!(
+            serde_json::from_str::<CounterKey>(r#""requests""#).unwrap(),
+            key
+        );
+    }
+
+    #[test]
+    fn metric_ref_is_an_id_or_a_key() {
+        assert_eq!(serde_json::to_string(&MetricRef::Id(3)).unwrap(), "3");
+        assert_eq!(
+            serde_json::from_str::<MetricRef>("3").unwrap(),
+            MetricRef::Id(3)
+        );
+        assert_eq!(
+            serde_json::from_str::<MetricRef>(r#""requests""#).unwrap(),
+            "requests".into()
+        );
+    }
+
+    #[test]
+    fn labeled_key_round_trips() {
+        let key = CounterKey::new("requests")
+            .label("status", "500")
+            .label("route", "/a");
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(serde_json::from_str::<CounterKey>(&json).unwrap(), key);
+    }
+
+    #[test]
+    fn counter_states_in_minutes_are_read_as_seconds() {
+        let state = CounterState {
+            epoch_seconds: 600,
+            count: 3,
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(json, r#"{"epoch_seconds":600,"count":3}"#);
+        assert_eq!(serde_json::from_str::<CounterState>(&json).unwrap(), state);
+        assert_eq!(
+            serde_json::from_str::<CounterState>(r#"{"epoch_minutes":10,"count":3}"#).unwrap(),
+            state
+        );
+    }
+
+    #[test]
+    fn gauge_merge_and_combine() {
+        let mut host_a = GaugeState::new(10, 4.0);
+        host_a.record(2.0);
+        host_a.record(3.0);
+        assert_eq!((host_a.last, host_a.min, host_a.max), (3.0, 2.0, 4.0));
+        assert_eq!(host_a.avg(), 3.0);
+
+        let mut later = host_a.clone();
+        later.merge(GaugeState::new(11, 8.0));
+        assert_eq!((later.last, later.max, later.count), (8.0, 8.0, 4));
+
+        let mut total = host_a;
+        total.combine(GaugeState::new(10, 1.0));
+        assert_eq!((total.last, total.min, total.count), (4.0, 1.0, 4));
+    }
+
+    #[test]
+    fn histogram_quantiles_are_within_bucket_error() {
+        let mut histogram = HistogramState::new(10, 1.0);
+        (2..=1000).for_each(|value| histogram.record(value as f64));
+
+        for (q, expected) in [(0.5, 500.0), (0.9, 900.0), (0.99, 990.0)] {
+            let value = histogram.quantile(q).unwrap();
+            assert!(
+                (value - expected).abs() / expected < 0.03,
+                "{} {}",
+                q,
+                value
+            );
+        }
+        assert_eq!(histogram.quantile(0.0), Some(1.0));
+        assert_eq!(histogram.quantile(1.0), Some(1000.0));
+    }
+
+    #[test]
+    fn histogram_ignores_non_finite_samples() {
+        let mut histogram = HistogramState::new(10, 2.0);
+        histogram.record(f64::NAN);
+        histogram.record(f64::INFINITY);
+        histogram.record(f64::NEG_INFINITY);
+        assert_eq!((histogram.count, histogram.sum), (1, 2.0));
+
+        histogram.record(-3.0);
+        histogram.record(0.0);
+        assert_eq!((histogram.count, histogram.zero_count), (3, 2));
+        assert_eq!((histogram.sum, histogram.min), (-1.0, -3.0));
+        assert_eq!(histogram.quantile(0.0), Some(-3.0));
+        assert_eq!(histogram.max, 2.0);
+    }
+
+    #[test]
+    fn histograms_merge_by_adding_buckets() {
+        let mut low = HistogramState::new(10, 0.0);
+        (1..=50).for_each(|value| low.record(value as f64));
+        let mut high = HistogramState::new(11, 51.0);
+        (52..=100).for_each(|value| high.record(value as f64));
+
+        low.merge(high);
+
+        assert_eq!((low.count, low.zero_count, low.max), (101, 1, 100.0));
+        let median = low.quantile(0.5).unwrap();
+        assert!((median - 50.0).abs() / 50.0 < 0.03);
+        assert!(low.quantile(0.9).unwrap() > 85.0);
+    }
 
-    epoch_seconds / 60
+    #[test]
+    fn key_matches_label_subset() {
+        let key = CounterKey::new("requests")
+            .label("status", "500")
+            .label("route", "/a");
+        assert!(key.matches("requests", &Labels::new()));
+        assert!(key.matches(
+            "requests",
+            &CounterKey::new("").label("status", "500").labels
+        ));
+        assert!(!key.matches(
+            "requests",
+            &CounterKey::new("").label("status", "200").labels
+        ));
+        assert!(!key.matches("responses", &Labels::new()));
+    }
 }
@@ -0,0 +1,364 @@
+// Write-ahead log of the updates the counter server applies, so a crash loses at most
+// what the fsync policy allows rather than everything since the last snapshot.
+//
+// The log is a directory of numbered segment files, each holding one JSON record per line.
+// Every record has a log sequence number (LSN) that increases across segments. A checkpoint
+// takes a snapshot that notes the last LSN it covers and starts a new segment, then deletes
+// the older segments once the snapshot is on disk. Replay skips records the snapshot covers,
+// so a crash at any point of a checkpoint neither loses nor double applies an update.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::counter_types::CounterMessage;
+use crate::error::Error;
+
+const SEGMENT_EXTENSION: &str = "wal";
+
+/// When appended records are flushed to disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every record. No applied update is lost, at the cost of a disk flush per update.
+    Always,
+    /// Within this long of a record being appended, by a later append or the server's timer.
+    /// A power failure loses up to this long of updates.
+    Interval(Duration),
+    /// Left to the OS. Survives the server crashing, but not the machine.
+    Never,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct WalRecord<M = CounterMessage> {
+    pub lsn: u64,
+    /// An update, with its metrics named by key rather than connection id
+    pub message: M,
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    segment: u64,
+    file: File,
+    /// Length of the current segment's whole records
+    len: u64,
+    fsync: FsyncPolicy,
+    last_sync: Instant,
+    /// Records have been appended since the last sync
+    unsynced: bool,
+    last_lsn: u64,
+}
+
+impl Wal {
+    /// Open the log in `dir`, creating it if needed.
+    /// Returns the records after `after_lsn`, the last LSN a snapshot covers, for replay.
+    pub fn open(
+        dir: &Path,
+        fsync: FsyncPolicy,
+        after_lsn: u64,
+    ) -> Result<(Wal, Vec<WalRecord>), Error> {
+        fs::create_dir_all(dir)?;
+        let segments = segments(dir)?;
+
+        let mut last_lsn = after_lsn;
+        let mut records = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            let path = segment_path(dir, *segment);
+            let (read, valid_len) = read_segment(&path)?;
+            if valid_len < fs::metadata(&path)?.len() {
+                if i + 1 < segments.len() {
+                    return Err(Error::Config(format!(
+                        "{}: corrupt record before the last segment",
+                        path.display()
+                    )));
+                }
+                // A write torn by a crash. Drop it so new records start on a clean line.
+                eprintln!("Truncating a torn record at the end of {}", path.display());
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid_len)?;
+            }
+            for record in read {
+                last_lsn = last_lsn.max(record.lsn);
+                if record.lsn > after_lsn {
+                    records.push(record);
+                }
+            }
+        }
+
+        let segment = segments.last().copied().unwrap_or(1);
+        let file = open_segment(dir, segment)?;
+        let wal = Wal {
+            len: file.metadata()?.len(),
+            file,
+            dir: dir.to_path_buf(),
+            segment,
+            fsync,
+            last_sync: Instant::now(),
+            unsynced: false,
+            last_lsn,
+        };
+        Ok((wal, records))
+    }
+
+    /// The LSN of the last record appended
+    pub fn last_lsn(&self) -> u64 {
+        self.last_lsn
+    }
+
+    /// A record that fails to write is rolled back, so later records still replay.
+    /// A failed fsync leaves the record in place, but it may not survive a power failure.
+    pub fn append(&mut self, message: &CounterMessage) -> Result<(), Error> {
+        let record = WalRecord {
+            lsn: self.last_lsn + 1,
+            message,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            // Part of the line may have been written, which would stop replay there
+            if let Err(truncate) = self.file.set_len(self.len) {
+                eprintln!(
+                    "Failed to roll back a partly written log record: {}",
+                    truncate
+                );
+            }
+            return Err(e.into());
+        }
+        self.len += line.len() as u64;
+        self.last_lsn += 1;
+        self.unsynced = true;
+
+        match self.fsync {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// How often the owner should call `sync_pending`, for the `Interval` policy
+    pub fn sync_interval(&self) -> Option<Duration> {
+        match self.fsync {
+            FsyncPolicy::Interval(interval) => Some(interval),
+            _ => None,
+        }
+    }
+
+    /// Sync the records appended since the last sync, so they don't wait for another append
+    pub fn sync_pending(&mut self) -> Result<(), Error> {
+        if !self.unsynced {
+            return Ok(());
+        }
+        self.sync()
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Start a new segment for the records after a checkpoint.
+    /// Returns the sealed segment, to remove once the checkpoint is on disk.
+    pub fn rotate(&mut self) -> Result<u64, Error> {
+        self.sync()?;
+        let sealed = self.segment;
+        self.file = open_segment(&self.dir, sealed + 1)?;
+        self.len = 0;
+        self.segment = sealed + 1;
+        Ok(sealed)
+    }
+
+    /// Delete the segments up to and including `sealed`
+    pub fn remove_through(&mut self, sealed: u64) -> Result<(), Error> {
+        for segment in segments(&self.dir)? {
+            if segment <= sealed {
+                fs::remove_file(segment_path(&self.dir, segment))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+impl Wal {
+    /// Make every later write fail, as if the disk were full
+    pub(crate) fn fail_writes(&mut self) {
+        self.file = OpenOptions::new().write(true).open("/dev/full").unwrap();
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", segment, SEGMENT_EXTENSION))
+}
+
+fn open_segment(dir: &Path, segment: u64) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, segment))?)
+}
+
+/// Segment numbers in `dir`, oldest first
+fn segments(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+            if let Some(segment) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                segments.push(segment);
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// The segment's records up to the first that doesn't parse, and the length they span
+fn read_segment(path: &Path) -> Result<(Vec<WalRecord>, u64), Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut valid_len = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        match serde_json::from_slice(&line) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+        valid_len += read as u64;
+    }
+    Ok((records, valid_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+    use crate::counter_types::{CounterKey, CounterState, CounterUpdateMessage};
+
+    fn temp_wal_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "async-pub-{}-{}-{}",
+            name,
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ))
+    }
+
+    fn update(count: u64) -> CounterMessage {
+        CounterMessage::Update(CounterUpdateMessage {
+            counter: CounterKey::new("a").into(),
+            state: vec![CounterState {
+                epoch_seconds: 10,
+                count,
+            }],
+            source: 0,
+            seq: 0,
+        })
+    }
+
+    fn counts(records: &[WalRecord]) -> Vec<u64> {
+        records
+            .iter()
+            .map(|record| match &record.message {
+                CounterMessage::Update(update) => update.state[0].count,
+                _ => panic!("expected an update"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_replay_after_the_checkpoint() {
+        let dir = temp_wal_dir("wal");
+        let (mut wal, records) = Wal::open(&dir, FsyncPolicy::Always, 0).unwrap();
+        assert!(records.is_empty());
+        wal.append(&update(1)).unwrap();
+        wal.append(&update(2)).unwrap();
+
+        // A checkpoint covering the first two, whose segment removal never happened
+        let checkpoint = wal.last_lsn();
+        wal.rotate().unwrap();
+        wal.append(&update(3)).unwrap();
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(&dir, FsyncPolicy::Never, checkpoint).unwrap();
+        assert_eq!(counts(&records), vec![3]);
+        assert_eq!(records[0].lsn, 3);
+        assert_eq!(wal.last_lsn(), 3);
+
+        let sealed = wal.rotate().unwrap();
+        wal.remove_through(sealed).unwrap();
+        assert_eq!(segments(&dir).unwrap(), vec![sealed + 1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_appends_are_not_logged() {
+        let dir = temp_wal_dir("wal-full");
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        wal.append(&update(1)).unwrap();
+        wal.fail_writes();
+        assert!(matches!(wal.append(&update(2)), Err(Error::Transport(_))));
+        assert_eq!(wal.last_lsn(), 1);
+        drop(wal);
+
+        let (_, records) = Wal::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        assert_eq!(counts(&records), vec![1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interval_policy_syncs_pending_records_on_request() {
+        let dir = temp_wal_dir("wal-interval");
+        let interval = Duration::from_secs(3600);
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Interval(interval), 0).unwrap();
+        assert_eq!(wal.sync_interval(), Some(interval));
+        // Within the interval of opening, so left for the timer
+        wal.append(&update(1)).unwrap();
+        assert!(wal.unsynced);
+        wal.sync_pending().unwrap();
+        assert!(!wal.unsynced);
+
+        let (wal, _) = Wal::open(&dir, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(wal.sync_interval(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let dir = temp_wal_dir("wal-torn");
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        wal.append(&update(1)).unwrap();
+        drop(wal);
+        let path = segment_path(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"lsn": 2, "mess"#).unwrap();
+
+        let (mut wal, records) = Wal::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        assert_eq!(counts(&records), vec![1]);
+        wal.append(&update(2)).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        assert_eq!(counts(&records), vec![1, 2]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+use std::io;
+
+use crate::counter_types::QueryError;
+use crate::sidecar::PushError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The logger was closed, or the publisher it feeds has stopped
+    Closed,
+    /// A bounded queue had no room for the item
+    Full,
+    /// The publisher could not handle the item
+    PublisherFailed(String),
+    /// Moving the item to another process or host failed
+    Transport(io::Error),
+    /// A setting was missing or invalid
+    Config(String),
+    /// A recorded value can't be represented, ie. a NaN sample
+    InvalidValue(String),
+    /// The operation isn't available on this kind of logger or connection
+    Unsupported(String),
+    /// The counter server could not answer a query
+    Query(QueryError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Closed => write!(f, "closed"),
+            Error::Full => write!(f, "queue is full"),
+            Error::PublisherFailed(reason) => write!(f, "publisher failed: {}", reason),
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::Config(reason) => write!(f, "invalid config: {}", reason),
+            Error::InvalidValue(reason) => write!(f, "invalid value: {}", reason),
+            Error::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            Error::Query(e) => write!(f, "query failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Transport(e.into())
+    }
+}
+
+impl From<PushError> for Error {
+    fn from(e: PushError) -> Self {
+        match e {
+            PushError::Full => Error::Full,
+            PushError::TooLarge => Error::Transport(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record is larger than a queue slot",
+            )),
+            PushError::Abandoned => Error::Transport(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "write stalled and was abandoned by the consumer",
+            )),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::{Arc, RwLock};
+
+/// A safe container for globals such as static loggers and publisher configs.
+/// Can be const constructed, then set once or replaced at runtime.
+/// Readers get an `Arc`, so a replaced value stays alive for anyone still using it.
+pub struct GlobalCell<T> {
+    value: RwLock<Option<Arc<T>>>,
+}
+
+impl<T> GlobalCell<T> {
+    pub const fn new() -> GlobalCell<T> {
+        GlobalCell {
+            value: RwLock::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<Arc<T>> {
+        self.value.read().unwrap().clone()
+    }
+
+    /// Set the value if the cell is empty, otherwise hand the value back
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut current = self.value.write().unwrap();
+        if current.is_some() {
+            return Err(value);
+        }
+        *current = Some(Arc::new(value));
+        Ok(())
+    }
+
+    /// Set the value, returning the previous one
+    pub fn replace(&self, value: T) -> Option<Arc<T>> {
+        self.value.write().unwrap().replace(Arc::new(value))
+    }
+
+    pub fn take(&self) -> Option<Arc<T>> {
+        self.value.write().unwrap().take()
+    }
+
+    pub fn get_or_init<F>(&self, init: F) -> Arc<T>
+    where
+        F: FnOnce() -> T,
+    {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        // Another thread may have set the value between the locks
+        self.value
+            .write()
+            .unwrap()
+            .get_or_insert_with(|| Arc::new(init()))
+            .clone()
+    }
+}
+
+impl<T> Default for GlobalCell<T> {
+    fn default() -> Self {
+        GlobalCell::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static CELL: GlobalCell<String> = GlobalCell::new();
+
+    #[test]
+    fn set_once_then_replace() {
+        assert!(CELL.get().is_none());
+        CELL.set("first".to_string()).unwrap();
+        assert_eq!(CELL.set("second".to_string()), Err("second".to_string()));
+
+        let first = CELL.get().unwrap();
+        let previous = CELL.replace("third".to_string()).unwrap();
+        assert_eq!(*previous, "first");
+        // Readers keep the value they got
+        assert_eq!(*first, "first");
+        assert_eq!(*CELL.get().unwrap(), "third");
+
+        assert_eq!(*CELL.take().unwrap(), "third");
+        assert!(CELL.get().is_none());
+    }
+
+    #[test]
+    fn get_or_init_runs_once() {
+        let cell: GlobalCell<u32> = GlobalCell::new();
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+        assert_eq!(*cell.get_or_init(|| 2), 1);
+    }
+}
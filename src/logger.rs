@@ -1,11 +1,29 @@
-use std::cell::UnsafeCell;
+use std::io;
 use std::mem::swap;
 // switch to tokio::sync::mpsc
-use std::sync::mpsc::{channel, Sender};
-use std::sync::RwLock;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::context::Context;
+use crate::error::Error;
+use crate::redaction::{Redact, Redactor};
+use crate::sidecar::{SidecarProducer, SidecarTarget};
+
+/// Transforms each record on the publisher thread before it is passed to the Publisher
+pub type Stage<T> = Box<dyn FnMut(&mut T, &mut Context) + Send>;
+
+// Type erased so only `new_sidecar` needs T: Serialize
+type SidecarSend<T> = Box<dyn Fn(T, Context) -> Result<(), Error> + Send + Sync>;
+
+enum LoggerMessage<T> {
+    Record(T, Context),
+    AddStage(Stage<T>),
+}
 
 pub struct Logger<T> {
     // Lazy: Allows doing the complex (non const) initialization of the state
@@ -22,28 +40,159 @@ impl<T> Logger<T> {
     {
         Self {
             state: Lazy::new(move || {
-                let (tx, rx) = channel::<T>();
+                let (tx, rx) = channel::<LoggerMessage<T>>();
+                let publisher_error = Arc::new(Mutex::new(None));
+                let thread_publisher_error = publisher_error.clone();
 
                 let publisher_thread = std::thread::spawn(move || {
                     let mut publisher = P::new();
+                    let mut stages: Vec<Stage<T>> = Vec::new();
+                    let tick_interval = publisher.tick_interval();
+                    let mut next_tick = tick_interval.map(|interval| Instant::now() + interval);
+                    let mut drain_due = false;
                     // This thread will run so long as there is a sender alive.
                     // Since common sender keeps an instance of the sender,
                     // the thread runs until common sender is dropped or
                     // set_new_channel is called again.
-                    for data in rx {
-                        let _ = publisher.send(data);
+                    loop {
+                        let message = match rx.try_recv() {
+                            Ok(message) => Some(message),
+                            Err(TryRecvError::Disconnected) => break,
+                            Err(TryRecvError::Empty) => {
+                                // Everything queued has been handled, so let the publisher
+                                // send what it buffered before waiting for more
+                                if drain_due {
+                                    drain_due = false;
+                                    if let Err(e) = publisher.drained() {
+                                        *thread_publisher_error.lock().unwrap() = Some(e);
+                                    }
+                                }
+                                match next_tick {
+                                    Some(tick_at) => {
+                                        match rx.recv_timeout(
+                                            tick_at.saturating_duration_since(Instant::now()),
+                                        ) {
+                                            Ok(message) => Some(message),
+                                            Err(RecvTimeoutError::Timeout) => None,
+                                            Err(RecvTimeoutError::Disconnected) => break,
+                                        }
+                                    }
+                                    None => match rx.recv() {
+                                        Ok(message) => Some(message),
+                                        Err(_) => break,
+                                    },
+                                }
+                            }
+                        };
+
+                        if let (Some(tick_at), Some(interval)) = (next_tick, tick_interval) {
+                            if Instant::now() >= tick_at {
+                                if let Err(e) = publisher.tick() {
+                                    *thread_publisher_error.lock().unwrap() = Some(e);
+                                }
+                                next_tick = Some(Instant::now() + interval);
+                            }
+                        }
+
+                        let Some(message) = message else {
+                            continue;
+                        };
+                        match message {
+                            LoggerMessage::Record(mut data, mut context) => {
+                                for stage in stages.iter_mut() {
+                                    stage(&mut data, &mut context);
+                                }
+                                if let Err(e) = publisher.send_with_context(data, context) {
+                                    *thread_publisher_error.lock().unwrap() = Some(e);
+                                }
+                                drain_due = true;
+                            }
+                            LoggerMessage::AddStage(stage) => stages.push(stage),
+                        }
                     }
                 });
 
                 RwLock::new(Some(LoggerState {
                     publisher_handle: Some(publisher_thread),
                     tx: Some(tx),
+                    sidecar: None,
+                    publisher_error,
                 }))
             }),
         }
     }
 
-    pub fn send(&self, data: T) -> Result<(), ()> {
+    /// A logger without a publisher thread. Records are written to a shared memory queue
+    /// that a separate `Sidecar` process drains into its Publisher.
+    /// Stages run in the sidecar, so `add_stage` is rejected on this logger.
+    /// If the queue can't be opened, every send fails with the reason as `Error::Transport`.
+    pub const fn new_sidecar<S>() -> Logger<T>
+    where
+        S: SidecarTarget,
+        T: Serialize + Send + 'static,
+    {
+        Self {
+            state: Lazy::new(move || {
+                let path = S::path();
+                let sidecar: SidecarSend<T> = match SidecarProducer::<T>::open(&path, S::config()) {
+                    Ok(sidecar) => Box::new(move |data, context| sidecar.send(data, context)),
+                    // Every send fails with the reason the queue couldn't be opened
+                    Err(e) => {
+                        let kind = e.kind();
+                        let reason = format!("sidecar queue {}: {}", path.display(), e);
+                        Box::new(move |_, _| {
+                            Err(Error::Transport(io::Error::new(kind, reason.clone())))
+                        })
+                    }
+                };
+                RwLock::new(Some(LoggerState {
+                    publisher_handle: None,
+                    tx: None,
+                    sidecar: Some(sidecar),
+                    publisher_error: Arc::new(Mutex::new(None)),
+                }))
+            }),
+        }
+    }
+
+    /// Queue data for the publisher along with the context fields in scope on the calling thread or task
+    pub fn send(&self, data: T) -> Result<(), Error> {
+        self.send_message(LoggerMessage::Record(data, Context::current()))
+    }
+
+    /// Run a stage over every record sent after this call, after any previously added stages
+    pub fn add_stage<S>(&self, stage: S) -> Result<(), Error>
+    where
+        S: FnMut(&mut T, &mut Context) + Send + 'static,
+    {
+        self.send_message(LoggerMessage::AddStage(Box::new(stage)))
+    }
+
+    /// Scrub every record and its context fields with the redactor before publishing.
+    /// The caller can keep a clone of the redactor to read its per rule counts.
+    pub fn redact_with(&self, redactor: Arc<Redactor>) -> Result<(), Error>
+    where
+        T: Redact,
+    {
+        self.add_stage(move |data: &mut T, context: &mut Context| {
+            redactor.redact(data);
+            redactor.redact(context);
+        })
+    }
+
+    fn send_message(&self, message: LoggerMessage<T>) -> Result<(), Error> {
+        {
+            let state = self.state.read().unwrap();
+            if let Some(sidecar) = state.as_ref().and_then(|state| state.sidecar.as_ref()) {
+                return match message {
+                    LoggerMessage::Record(data, context) => sidecar(data, context),
+                    LoggerMessage::AddStage(_) => Err(Error::Unsupported(
+                        "stages of a sidecar logger run in the sidecar process".to_string(),
+                    )),
+                };
+            }
+        }
+
         // Must clone the sender to ensure the same sender not used by multiple threads
         // The clone is dropped, but the original sender must stay alive
         // to keep the channel open
@@ -53,8 +202,18 @@ impl<T> Logger<T> {
             .unwrap()
             .as_ref()
             .map(|state| state.tx.as_ref().unwrap().clone())
-            .ok_or(())?;
-        s.send(data).map_err(|_| ())
+            .ok_or(Error::Closed)?;
+        s.send(message).map_err(|_| Error::Closed)
+    }
+
+    /// The most recent error returned by the publisher since the last call.
+    /// Publishing happens on the background thread, so failures can't be returned from `send`.
+    pub fn take_publisher_error(&self) -> Option<Error> {
+        self.state
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|state| state.publisher_error.lock().unwrap().take())
     }
 
     pub fn close(&self) {
@@ -66,42 +225,175 @@ impl<T> Logger<T> {
 
 pub trait Publisher<T> {
     fn new() -> Self;
-    fn send(&mut self, data: T) -> Result<(), ()>;
+    fn send(&mut self, data: T) -> Result<(), Error>;
+
+    /// Receives each item with the context captured when it was sent.
+    /// Publishers that don't use context can implement just `send`.
+    fn send_with_context(&mut self, data: T, _context: Context) -> Result<(), Error> {
+        self.send(data)
+    }
+
+    /// How often the publisher thread calls `tick`, even while no records arrive.
+    /// None, the default, never ticks.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Periodic work such as flushing buffered data
+    fn tick(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called once every queued record has been sent, so a publisher that buffers
+    /// can write the records that arrived together as one batch
+    fn drained(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 struct LoggerState<T> {
     // Store state fields as options so they can be safely dropped manually
     // The thread handle is stored so it can be joined when the logger is dropped
     publisher_handle: Option<JoinHandle<()>>,
-    tx: Option<Sender<T>>,
+    tx: Option<Sender<LoggerMessage<T>>>,
+    // Set instead of the thread and sender when records go to a sidecar process
+    sidecar: Option<SidecarSend<T>>,
+    // Written by the publisher thread, taken by callers
+    publisher_error: Arc<Mutex<Option<Error>>>,
 }
 
 impl<T> Drop for LoggerState<T> {
     fn drop(&mut self) {
         // Close the sender so the publisher thread can exit
         {
-            let mut cleared_tx: Option<Sender<T>> = None;
+            let mut cleared_tx: Option<Sender<LoggerMessage<T>>> = None;
             swap(&mut self.tx, &mut cleared_tx);
         }
         // Wait for the publisher thread to exit
         let mut thread_handle: Option<JoinHandle<()>> = None;
         swap(&mut self.publisher_handle, &mut thread_handle);
-        thread_handle.unwrap().join().unwrap();
+        if let Some(thread_handle) = thread_handle {
+            thread_handle.join().unwrap();
+        }
     }
 }
 
 unsafe impl<T> Sync for Logger<T> {}
 
-pub struct UnsafeSyncCell<T> {
-    pub data: UnsafeCell<T>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingPublisher;
+
+    impl Publisher<u8> for FailingPublisher {
+        fn new() -> Self {
+            FailingPublisher
+        }
+
+        fn send(&mut self, data: u8) -> Result<(), Error> {
+            Err(Error::PublisherFailed(format!("rejected {}", data)))
+        }
+    }
+
+    static TICKS: Mutex<u32> = Mutex::new(0);
+
+    struct TickingPublisher;
 
-unsafe impl<T> Sync for UnsafeSyncCell<T> {}
+    impl Publisher<u8> for TickingPublisher {
+        fn new() -> Self {
+            TickingPublisher
+        }
+
+        fn send(&mut self, _data: u8) -> Result<(), Error> {
+            Ok(())
+        }
 
-impl<T> UnsafeSyncCell<T> {
-    pub const fn new(data: T) -> UnsafeSyncCell<T> {
-        UnsafeSyncCell {
-            data: UnsafeCell::new(data),
+        fn tick_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
         }
+
+        fn tick(&mut self) -> Result<(), Error> {
+            *TICKS.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    static DRAINED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    /// Records how many items it was sent before each drain
+    struct DrainingPublisher {
+        unflushed: u32,
+    }
+
+    impl Publisher<u8> for DrainingPublisher {
+        fn new() -> Self {
+            DrainingPublisher { unflushed: 0 }
+        }
+
+        fn send(&mut self, _data: u8) -> Result<(), Error> {
+            self.unflushed += 1;
+            Ok(())
+        }
+
+        fn drained(&mut self) -> Result<(), Error> {
+            DRAINED.lock().unwrap().push(self.unflushed);
+            self.unflushed = 0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn publisher_is_told_when_the_queue_drains() {
+        let logger: Logger<u8> = Logger::new::<DrainingPublisher>();
+        for data in 0..100 {
+            logger.send(data).unwrap();
+        }
+        while DRAINED.lock().unwrap().iter().sum::<u32>() < 100 {
+            std::thread::yield_now();
+        }
+        logger.close();
+
+        // Each drain follows at least one item, and nothing goes undrained
+        assert!(DRAINED.lock().unwrap().iter().all(|&items| items > 0));
+    }
+
+    #[test]
+    fn publisher_ticks_without_records() {
+        let logger: Logger<u8> = Logger::new::<TickingPublisher>();
+        logger.send(1).unwrap();
+        while *TICKS.lock().unwrap() < 3 {
+            std::thread::yield_now();
+        }
+        logger.close();
+    }
+
+    #[test]
+    fn publisher_errors_are_reported() {
+        let logger: Logger<u8> = Logger::new::<FailingPublisher>();
+        logger.send(7).unwrap();
+        // Wait for the publisher thread to handle the item
+        while logger
+            .state
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .publisher_error
+            .lock()
+            .unwrap()
+            .is_none()
+        {
+            std::thread::yield_now();
+        }
+
+        match logger.take_publisher_error() {
+            Some(Error::PublisherFailed(reason)) => assert_eq!(reason, "rejected 7"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(logger.take_publisher_error().is_none());
+
+        logger.close();
+        assert!(matches!(logger.send(8), Err(Error::Closed)));
     }
 }
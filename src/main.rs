@@ -1,13 +1,45 @@
+// Only one test main runs, so outside of tests most of each module's API goes unused.
+// Test builds still check every module for dead code.
+#[cfg_attr(not(test), allow(dead_code))]
 mod logger;
+#[cfg_attr(not(test), allow(dead_code))]
+mod error;
+#[cfg_attr(not(test), allow(dead_code))]
+mod global;
+#[cfg_attr(not(test), allow(dead_code))]
+mod context;
+#[cfg_attr(not(test), allow(dead_code))]
+mod redaction;
+#[cfg_attr(not(test), allow(dead_code))]
+mod sidecar;
+#[cfg_attr(not(test), allow(dead_code))]
 mod counter;
+#[cfg_attr(not(test), allow(dead_code))]
+mod counter_client;
+#[cfg_attr(not(test), allow(dead_code))]
+mod counter_config;
+#[cfg_attr(not(test), allow(dead_code))]
+mod counter_retention;
+#[cfg_attr(not(test), allow(dead_code))]
 mod counter_server;
+#[cfg_attr(not(test), allow(dead_code))]
+mod counter_snapshot;
+#[cfg_attr(not(test), allow(dead_code))]
 mod counter_types;
+#[cfg_attr(not(test), allow(dead_code))]
+mod counter_wal;
+#[cfg(test)]
+mod test_util;
 
+#[allow(dead_code)]
 mod test1;
+#[allow(dead_code)]
 mod test2;
+#[allow(dead_code)]
 mod test3;
 // mod test4;
 // mod test5;
+#[allow(dead_code)]
 mod test6;
 mod test7;
 
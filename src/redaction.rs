@@ -0,0 +1,226 @@
+// Redaction stage run on the publisher thread, before records reach the Publisher
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use regex::Regex;
+
+use crate::context::Context;
+
+const DEFAULT_REPLACEMENT: &str = "[REDACTED]";
+
+pub enum RedactionRule {
+    /// Replace the whole value of any field with this name (case insensitive)
+    Field(String),
+    /// Replace every match of the pattern in any field value
+    Pattern(Regex),
+}
+
+impl fmt::Display for RedactionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedactionRule::Field(name) => write!(f, "field:{}", name),
+            RedactionRule::Pattern(regex) => write!(f, "pattern:{}", regex.as_str()),
+        }
+    }
+}
+
+/// Records that expose named string fields to a redactor.
+/// Unnamed values (ie. a plain message) use the empty string as their name.
+pub trait Redact {
+    fn redact_fields(&mut self, redact: &mut dyn FnMut(&str, &mut String));
+}
+
+pub struct Redactor {
+    rules: Vec<(RedactionRule, AtomicU64)>,
+    replacement: String,
+}
+
+impl Redactor {
+    pub fn new() -> Redactor {
+        Redactor {
+            rules: Vec::new(),
+            replacement: DEFAULT_REPLACEMENT.to_string(),
+        }
+    }
+
+    pub fn field(self, name: impl Into<String>) -> Redactor {
+        self.rule(RedactionRule::Field(name.into()))
+    }
+
+    pub fn pattern(self, pattern: &str) -> Result<Redactor, regex::Error> {
+        Ok(self.rule(RedactionRule::Pattern(Regex::new(pattern)?)))
+    }
+
+    pub fn rule(mut self, rule: RedactionRule) -> Redactor {
+        self.rules.push((rule, AtomicU64::new(0)));
+        self
+    }
+
+    pub fn replacement(mut self, replacement: impl Into<String>) -> Redactor {
+        self.replacement = replacement.into();
+        self
+    }
+
+    /// Apply the rules in order to a single field value
+    pub fn redact_value(&self, name: &str, value: &mut String) {
+        for (rule, count) in self.rules.iter() {
+            match rule {
+                RedactionRule::Field(field) => {
+                    if field.eq_ignore_ascii_case(name) {
+                        *value = self.replacement.clone();
+                        count.fetch_add(1, Ordering::Relaxed);
+                        // Nothing is left for later rules to match
+                        return;
+                    }
+                }
+                RedactionRule::Pattern(regex) => {
+                    let matches = regex.find_iter(value).count();
+                    if matches > 0 {
+                        *value = regex
+                            .replace_all(value, regex::NoExpand(&self.replacement))
+                            .into_owned();
+                        count.fetch_add(matches as u64, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn redact<R: Redact + ?Sized>(&self, record: &mut R) {
+        record.redact_fields(&mut |name, value| self.redact_value(name, value));
+    }
+
+    /// Number of redactions applied by each rule, in the order the rules were added
+    pub fn counts(&self) -> Vec<(&RedactionRule, u64)> {
+        self.rules
+            .iter()
+            .map(|(rule, count)| (rule, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Redactor::new()
+    }
+}
+
+impl Redact for String {
+    fn redact_fields(&mut self, redact: &mut dyn FnMut(&str, &mut String)) {
+        redact("", self);
+    }
+}
+
+impl Redact for HashMap<String, String> {
+    fn redact_fields(&mut self, redact: &mut dyn FnMut(&str, &mut String)) {
+        self.iter_mut()
+            .for_each(|(name, value)| redact(name, value));
+    }
+}
+
+impl Redact for BTreeMap<String, String> {
+    fn redact_fields(&mut self, redact: &mut dyn FnMut(&str, &mut String)) {
+        self.iter_mut()
+            .for_each(|(name, value)| redact(name, value));
+    }
+}
+
+impl Redact for Vec<(String, String)> {
+    fn redact_fields(&mut self, redact: &mut dyn FnMut(&str, &mut String)) {
+        self.iter_mut()
+            .for_each(|(name, value)| redact(name, value));
+    }
+}
+
+impl Redact for Context {
+    fn redact_fields(&mut self, redact: &mut dyn FnMut(&str, &mut String)) {
+        self.fields_mut()
+            .iter_mut()
+            .for_each(|(name, value)| redact(name, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::context;
+    use crate::logger::Logger;
+    use crate::test_util::capture_publisher;
+
+    fn test_redactor() -> Redactor {
+        Redactor::new()
+            .field("password")
+            .pattern(r"[\w.+-]+@[\w-]+\.[\w.]+")
+            .unwrap()
+            .pattern(r"\b\d{4}[ -]?\d{4}[ -]?\d{4}[ -]?\d{4}\b")
+            .unwrap()
+    }
+
+    #[test]
+    fn redact_by_field_name() {
+        let redactor = test_redactor();
+        let mut record = BTreeMap::from([
+            ("Password".to_string(), "hunter2".to_string()),
+            ("user".to_string(), "bob".to_string()),
+        ]);
+
+        redactor.redact(&mut record);
+
+        assert_eq!(record["Password"], "[REDACTED]");
+        assert_eq!(record["user"], "bob");
+        assert_eq!(redactor.counts()[0].1, 1);
+    }
+
+    #[test]
+    fn redact_by_pattern_counts_each_match() {
+        let redactor = test_redactor();
+        let mut message =
+            "from a@example.com to b@example.org, card 4111 1111 1111 1111".to_string();
+
+        redactor.redact(&mut message);
+
+        assert_eq!(message, "from [REDACTED] to [REDACTED], card [REDACTED]");
+        let counts: Vec<u64> = redactor.counts().into_iter().map(|(_, c)| c).collect();
+        assert_eq!(counts, vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn replacement_is_literal() {
+        let redactor = Redactor::new().pattern("secret").unwrap().replacement("$0");
+        let mut message = "a secret".to_string();
+
+        redactor.redact(&mut message);
+
+        assert_eq!(message, "a $0");
+    }
+
+    capture_publisher!(CapturePublisher, PUBLISHED, String);
+
+    #[test]
+    fn logger_redacts_before_publish() {
+        let logger: Logger<String> = Logger::new::<CapturePublisher>();
+        let redactor = Arc::new(Redactor::new().field("token").pattern("hunter2").unwrap());
+        logger.redact_with(redactor.clone()).unwrap();
+
+        context::scope(Context::new().with("token", "abc"), || {
+            logger.send("password is hunter2".to_string()).unwrap();
+        });
+        logger.close();
+
+        let published = PUBLISHED.lock().unwrap();
+        assert_eq!(published[0].0, "password is [REDACTED]");
+        assert_eq!(published[0].1.get("token"), Some("[REDACTED]"));
+        assert_eq!(
+            redactor
+                .counts()
+                .into_iter()
+                .map(|(_, c)| c)
+                .collect::<Vec<_>>(),
+            vec![1, 1]
+        );
+    }
+}
@@ -0,0 +1,912 @@
+// Cross process logging: producers write records into a shared memory ring buffer
+// and a long lived sidecar process drains it into a Publisher.
+//
+// The ring is a bounded MPMC queue in the style of ringbuf / Vyukov's queue, where each
+// slot carries a sequence number. Producers never block: when the ring is full the record
+// is dropped and counted. Crash handling:
+// - Producer crash mid write: the slot stays reserved. The consumer abandons it once the
+//   writing process is gone or the slot has been stalled for `abandon_after`.
+// - Consumer crash: the read position lives in the shared header and only advances after
+//   the Publisher has accepted a record, so a restarted sidecar resumes where it stopped.
+//   A consumer lock held by pid is taken over once the holding process is gone.
+//   The sidecar takes the lock when it opens the queue and holds it until dropped.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use memmap2::MmapMut;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::context::Context;
+use crate::error::Error;
+use crate::logger::{Publisher, Stage};
+use crate::redaction::{Redact, Redactor};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"APUBSHMQ");
+const VERSION: u32 = 1;
+
+const INIT_EMPTY: u32 = 0;
+const INIT_IN_PROGRESS: u32 = 1;
+const INIT_READY: u32 = 2;
+
+// Header layout, in bytes
+const HEADER_SIZE: usize = 128;
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 8;
+const INIT_OFFSET: usize = 12;
+const SLOT_COUNT_OFFSET: usize = 16;
+const SLOT_SIZE_OFFSET: usize = 24;
+const HEAD_OFFSET: usize = 64;
+const TAIL_OFFSET: usize = 72;
+const CONSUMER_PID_OFFSET: usize = 80;
+const DROPPED_OFFSET: usize = 88;
+
+// Slot layout, in bytes
+const SLOT_HEADER_SIZE: usize = 32;
+const SEQ_OFFSET: usize = 0;
+const WRITER_PID_OFFSET: usize = 8;
+const LEN_OFFSET: usize = 12;
+const CHECKSUM_OFFSET: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShmQueueConfig {
+    pub slot_count: usize,
+    /// Bytes per slot, including a 32 byte slot header. Rounded up to a multiple of 8.
+    pub slot_size: usize,
+}
+
+impl Default for ShmQueueConfig {
+    fn default() -> Self {
+        ShmQueueConfig {
+            slot_count: 1024,
+            slot_size: 1024,
+        }
+    }
+}
+
+impl ShmQueueConfig {
+    fn aligned_slot_size(&self) -> usize {
+        self.slot_size.next_multiple_of(8)
+    }
+
+    fn file_len(&self) -> usize {
+        HEADER_SIZE + self.slot_count * self.aligned_slot_size()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// The consumer has fallen behind (or is gone) and every slot is in use
+    Full,
+    /// The record does not fit in a slot
+    TooLarge,
+    /// The consumer gave up on this write because it stalled
+    Abandoned,
+}
+
+/// A ring buffer in a memory mapped file, shared by any number of producer processes
+/// and a single consumer process
+pub struct ShmQueue {
+    map: MmapMut,
+    slot_count: u64,
+    slot_size: usize,
+    /// Canonical path of the file, identifying the queue within this process
+    path: PathBuf,
+}
+
+/// Queues a `ConsumerLock` in this process holds. The pid in the header can't tell
+/// two locks in one process apart from a lock left by an exited process with the same pid.
+static CONSUMED_HERE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+impl ShmQueue {
+    /// Open the queue at `path`, creating and initializing it if needed.
+    /// An existing queue must have been created with the same config.
+    pub fn open(path: &Path, config: ShmQueueConfig) -> io::Result<ShmQueue> {
+        if config.slot_count == 0 || config.slot_size <= SLOT_HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shared memory queue needs at least one slot larger than the slot header",
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() < config.file_len() as u64 {
+            // A zeroed file reads as INIT_EMPTY
+            file.set_len(config.file_len() as u64)?;
+        }
+        // SAFETY: the file is only modified through this type, which treats the mapping as
+        // shared memory and synchronizes through atomics in the header and slots
+        let map = unsafe { MmapMut::map_mut(&file)? };
+
+        let queue = ShmQueue {
+            map,
+            slot_count: config.slot_count as u64,
+            slot_size: config.aligned_slot_size(),
+            path: path.canonicalize()?,
+        };
+        queue.initialize(config)?;
+        Ok(queue)
+    }
+
+    fn initialize(&self, config: ShmQueueConfig) -> io::Result<()> {
+        let init = self.atomic_u32(INIT_OFFSET);
+        if init
+            .compare_exchange(
+                INIT_EMPTY,
+                INIT_IN_PROGRESS,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+        {
+            self.atomic_u64(MAGIC_OFFSET)
+                .store(MAGIC, Ordering::Relaxed);
+            self.atomic_u32(VERSION_OFFSET)
+                .store(VERSION, Ordering::Relaxed);
+            self.atomic_u64(SLOT_COUNT_OFFSET)
+                .store(self.slot_count, Ordering::Relaxed);
+            self.atomic_u64(SLOT_SIZE_OFFSET)
+                .store(self.slot_size as u64, Ordering::Relaxed);
+            for pos in 0..self.slot_count {
+                self.slot_atomic_u64(pos, SEQ_OFFSET)
+                    .store(pos, Ordering::Relaxed);
+            }
+            init.store(INIT_READY, Ordering::Release);
+        } else {
+            // Another process is initializing the queue
+            let start = Instant::now();
+            while init.load(Ordering::Acquire) != INIT_READY {
+                if start.elapsed() > Duration::from_secs(5) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "shared memory queue was never initialized",
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        if self.atomic_u64(MAGIC_OFFSET).load(Ordering::Relaxed) != MAGIC
+            || self.atomic_u32(VERSION_OFFSET).load(Ordering::Relaxed) != VERSION
+            || self.atomic_u64(SLOT_COUNT_OFFSET).load(Ordering::Relaxed)
+                != config.slot_count as u64
+            || self.atomic_u64(SLOT_SIZE_OFFSET).load(Ordering::Relaxed)
+                != config.aligned_slot_size() as u64
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared memory queue has a different format or config",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Largest record that fits in a slot
+    pub fn max_record_len(&self) -> usize {
+        self.slot_size - SLOT_HEADER_SIZE
+    }
+
+    /// Number of records dropped because the queue was full or a write was abandoned
+    pub fn dropped(&self) -> u64 {
+        self.atomic_u64(DROPPED_OFFSET).load(Ordering::Relaxed)
+    }
+
+    /// Number of records written but not yet consumed
+    pub fn len(&self) -> u64 {
+        let head = self.atomic_u64(HEAD_OFFSET).load(Ordering::Acquire);
+        let tail = self.atomic_u64(TAIL_OFFSET).load(Ordering::Acquire);
+        head.saturating_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy a record into the queue without blocking
+    pub fn push(&self, record: &[u8]) -> Result<(), PushError> {
+        if record.len() > self.max_record_len() {
+            self.atomic_u64(DROPPED_OFFSET)
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(PushError::TooLarge);
+        }
+
+        let head = self.atomic_u64(HEAD_OFFSET);
+        let mut pos = head.load(Ordering::Relaxed);
+        loop {
+            let seq = self
+                .slot_atomic_u64(pos, SEQ_OFFSET)
+                .load(Ordering::Acquire);
+            if seq == pos {
+                match head.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => break,
+                    Err(current) => pos = current,
+                }
+            } else if seq < pos {
+                // The slot still holds a record from the previous lap
+                self.atomic_u64(DROPPED_OFFSET)
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(PushError::Full);
+            } else {
+                pos = head.load(Ordering::Relaxed);
+            }
+        }
+
+        self.slot_atomic_u32(pos, WRITER_PID_OFFSET)
+            .store(std::process::id(), Ordering::Relaxed);
+        // SAFETY: this producer owns the slot until it publishes the sequence number below.
+        // If the consumer abandons the slot meanwhile, a torn payload fails the checksum.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                record.as_ptr(),
+                self.slot_payload_ptr(pos),
+                record.len(),
+            );
+        }
+        self.slot_atomic_u32(pos, LEN_OFFSET)
+            .store(record.len() as u32, Ordering::Relaxed);
+        self.slot_atomic_u64(pos, CHECKSUM_OFFSET)
+            .store(checksum(pos, record), Ordering::Relaxed);
+
+        self.slot_atomic_u64(pos, SEQ_OFFSET)
+            .compare_exchange(pos, pos + 1, Ordering::Release, Ordering::Relaxed)
+            .map(|_| ())
+            .map_err(|_| {
+                self.atomic_u64(DROPPED_OFFSET)
+                    .fetch_add(1, Ordering::Relaxed);
+                PushError::Abandoned
+            })
+    }
+
+    /// Take the consumer role. Only one live process may consume at a time,
+    /// holding at most one lock.
+    pub fn lock_consumer(self: &Arc<Self>) -> io::Result<ConsumerLock> {
+        {
+            let mut consumed = CONSUMED_HERE.lock().unwrap();
+            if consumed.contains(&self.path) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "shared memory queue is already consumed by this process",
+                ));
+            }
+            consumed.push(self.path.clone());
+        }
+
+        let consumer_pid = self.atomic_u32(CONSUMER_PID_OFFSET);
+        let pid = std::process::id();
+        loop {
+            // Our own pid here was left by an exited process, ie. in a restarted container
+            let current = consumer_pid.load(Ordering::Acquire);
+            if current != 0 && current != pid && process_alive(current) {
+                self.release_consumed_here();
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("shared memory queue is consumed by process {}", current),
+                ));
+            }
+            if consumer_pid
+                .compare_exchange(current, pid, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok(ConsumerLock {
+                    queue: self.clone(),
+                    stalled_since: None,
+                });
+            }
+        }
+    }
+
+    fn release_consumed_here(&self) {
+        CONSUMED_HERE
+            .lock()
+            .unwrap()
+            .retain(|path| *path != self.path);
+    }
+
+    fn header_ptr(&self, offset: usize) -> *mut u8 {
+        // Every offset used is within the header or a slot, which are inside the mapping
+        self.map.as_ptr().wrapping_add(offset) as *mut u8
+    }
+
+    fn atomic_u64(&self, offset: usize) -> &AtomicU64 {
+        // SAFETY: offsets are 8 byte aligned within a page aligned mapping that lives as long as self
+        unsafe { &*(self.header_ptr(offset) as *const AtomicU64) }
+    }
+
+    fn atomic_u32(&self, offset: usize) -> &AtomicU32 {
+        // SAFETY: offsets are 4 byte aligned within a page aligned mapping that lives as long as self
+        unsafe { &*(self.header_ptr(offset) as *const AtomicU32) }
+    }
+
+    fn slot_offset(&self, pos: u64) -> usize {
+        HEADER_SIZE + (pos % self.slot_count) as usize * self.slot_size
+    }
+
+    fn slot_atomic_u64(&self, pos: u64, offset: usize) -> &AtomicU64 {
+        self.atomic_u64(self.slot_offset(pos) + offset)
+    }
+
+    fn slot_atomic_u32(&self, pos: u64, offset: usize) -> &AtomicU32 {
+        self.atomic_u32(self.slot_offset(pos) + offset)
+    }
+
+    fn slot_payload_ptr(&self, pos: u64) -> *mut u8 {
+        self.header_ptr(self.slot_offset(pos) + SLOT_HEADER_SIZE)
+    }
+}
+
+/// Outcome of reading the oldest slot
+#[derive(Debug, PartialEq, Eq)]
+pub enum PopStatus {
+    /// A record was handled and released
+    Consumed,
+    /// Nothing has been written
+    Empty,
+    /// The oldest slot is still being written
+    Pending,
+    /// A crashed or stalled write was skipped
+    Abandoned,
+    /// A record failed its checksum and was skipped
+    Corrupt,
+}
+
+/// Held by the single consumer. Released when dropped.
+pub struct ConsumerLock {
+    queue: Arc<ShmQueue>,
+    stalled_since: Option<(u64, Instant)>,
+}
+
+impl ConsumerLock {
+    pub fn queue(&self) -> &ShmQueue {
+        &self.queue
+    }
+
+    /// Pass the oldest record to `handle`, then release its slot.
+    /// If this process dies inside `handle`, the record is handled again after restart.
+    pub fn pop_with<F>(&mut self, abandon_after: Duration, handle: F) -> PopStatus
+    where
+        F: FnOnce(&[u8]),
+    {
+        let queue = self.queue.clone();
+        let tail = queue.atomic_u64(TAIL_OFFSET);
+        let pos = tail.load(Ordering::Acquire);
+        let seq = queue.slot_atomic_u64(pos, SEQ_OFFSET);
+        let next_lap = pos + queue.slot_count;
+
+        if seq.load(Ordering::Acquire) != pos + 1 {
+            if queue.atomic_u64(HEAD_OFFSET).load(Ordering::Acquire) <= pos {
+                return PopStatus::Empty;
+            }
+
+            // Reserved by a producer that has not finished writing
+            let writer = queue
+                .slot_atomic_u32(pos, WRITER_PID_OFFSET)
+                .load(Ordering::Relaxed);
+            let stalled_since = match self.stalled_since {
+                Some((stalled_pos, since)) if stalled_pos == pos => since,
+                _ => {
+                    let now = Instant::now();
+                    self.stalled_since = Some((pos, now));
+                    now
+                }
+            };
+            let writer_gone = writer != 0 && !process_alive(writer);
+            if !writer_gone && stalled_since.elapsed() < abandon_after {
+                return PopStatus::Pending;
+            }
+
+            if seq
+                .compare_exchange(pos, next_lap, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                // The write completed just now
+                return PopStatus::Pending;
+            }
+            self.release(pos, None);
+            queue
+                .atomic_u64(DROPPED_OFFSET)
+                .fetch_add(1, Ordering::Relaxed);
+            return PopStatus::Abandoned;
+        }
+
+        let len = (queue
+            .slot_atomic_u32(pos, LEN_OFFSET)
+            .load(Ordering::Relaxed) as usize)
+            .min(queue.max_record_len());
+        let mut record = vec![0; len];
+        // SAFETY: the committed sequence number gives this consumer the slot until it is released
+        unsafe {
+            std::ptr::copy_nonoverlapping(queue.slot_payload_ptr(pos), record.as_mut_ptr(), len);
+        }
+
+        if checksum(pos, &record)
+            != queue
+                .slot_atomic_u64(pos, CHECKSUM_OFFSET)
+                .load(Ordering::Relaxed)
+        {
+            self.release(pos, Some(next_lap));
+            queue
+                .atomic_u64(DROPPED_OFFSET)
+                .fetch_add(1, Ordering::Relaxed);
+            return PopStatus::Corrupt;
+        }
+
+        handle(&record);
+        self.release(pos, Some(next_lap));
+        PopStatus::Consumed
+    }
+
+    fn release(&mut self, pos: u64, next_lap: Option<u64>) {
+        let queue = &self.queue;
+        queue
+            .slot_atomic_u32(pos, WRITER_PID_OFFSET)
+            .store(0, Ordering::Relaxed);
+        if let Some(next_lap) = next_lap {
+            queue
+                .slot_atomic_u64(pos, SEQ_OFFSET)
+                .store(next_lap, Ordering::Release);
+        }
+        queue
+            .atomic_u64(TAIL_OFFSET)
+            .store(pos + 1, Ordering::Release);
+        self.stalled_since = None;
+    }
+}
+
+impl Drop for ConsumerLock {
+    fn drop(&mut self) {
+        let _ = self.queue.atomic_u32(CONSUMER_PID_OFFSET).compare_exchange(
+            std::process::id(),
+            0,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        self.queue.release_consumed_here();
+    }
+}
+
+fn checksum(pos: u64, record: &[u8]) -> u64 {
+    // FNV-1a, seeded with the position so a record left over from an earlier lap fails
+    const PRIME: u64 = 0x100000001b3;
+    pos.to_le_bytes()
+        .iter()
+        .chain(record.iter())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(PRIME)
+        })
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    // Without a liveness check, stalled writes are abandoned by timeout alone
+    true
+}
+
+/// Where a sidecar mode Logger writes its records
+pub trait SidecarTarget {
+    fn path() -> PathBuf;
+
+    fn config() -> ShmQueueConfig {
+        ShmQueueConfig::default()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SidecarRecord<T> {
+    data: T,
+    context: Context,
+}
+
+/// Producer half used by `Logger::new_sidecar`
+pub(crate) struct SidecarProducer<T> {
+    queue: ShmQueue,
+    _record: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize> SidecarProducer<T> {
+    pub(crate) fn open(path: &Path, config: ShmQueueConfig) -> io::Result<SidecarProducer<T>> {
+        Ok(SidecarProducer {
+            queue: ShmQueue::open(path, config)?,
+            _record: PhantomData,
+        })
+    }
+
+    pub(crate) fn send(&self, data: T, context: Context) -> Result<(), Error> {
+        let record = serde_json::to_vec(&SidecarRecord { data, context })?;
+        Ok(self.queue.push(&record)?)
+    }
+}
+
+/// The long lived process that drains a shared memory queue into a Publisher.
+/// Holds the queue's consumer lock from `open` until dropped.
+pub struct Sidecar<T, P> {
+    consumer: ConsumerLock,
+    publisher: P,
+    stages: Vec<Stage<T>>,
+    poll_interval: Duration,
+    abandon_after: Duration,
+}
+
+impl<T, P> Sidecar<T, P>
+where
+    T: DeserializeOwned,
+    P: Publisher<T>,
+{
+    pub fn open(path: &Path, config: ShmQueueConfig) -> io::Result<Sidecar<T, P>> {
+        Ok(Sidecar {
+            consumer: Arc::new(ShmQueue::open(path, config)?).lock_consumer()?,
+            publisher: P::new(),
+            stages: Vec::new(),
+            poll_interval: Duration::from_millis(10),
+            abandon_after: Duration::from_secs(5),
+        })
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// How long a reserved slot may stall before the write is skipped,
+    /// even if the writing process still appears to be alive
+    pub fn abandon_after(mut self, abandon_after: Duration) -> Self {
+        self.abandon_after = abandon_after;
+        self
+    }
+
+    pub fn add_stage<S>(mut self, stage: S) -> Self
+    where
+        S: FnMut(&mut T, &mut Context) + Send + 'static,
+    {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn redact_with(self, redactor: Arc<Redactor>) -> Self
+    where
+        T: Redact,
+    {
+        self.add_stage(move |data: &mut T, context: &mut Context| {
+            redactor.redact(data);
+            redactor.redact(context);
+        })
+    }
+
+    pub fn queue(&self) -> &ShmQueue {
+        self.consumer.queue()
+    }
+
+    /// Publish every record currently in the queue. Returns the number published.
+    pub fn drain(&mut self) -> io::Result<usize> {
+        let Sidecar {
+            consumer,
+            publisher,
+            stages,
+            abandon_after,
+            ..
+        } = self;
+        let mut published = 0;
+        loop {
+            let status = consumer.pop_with(*abandon_after, |bytes| {
+                // Records that fail to parse come from an incompatible producer and are skipped
+                if let Ok(record) = serde_json::from_slice::<SidecarRecord<T>>(bytes) {
+                    let SidecarRecord {
+                        mut data,
+                        mut context,
+                    } = record;
+                    for stage in stages.iter_mut() {
+                        stage(&mut data, &mut context);
+                    }
+                    let _ = publisher.send_with_context(data, context);
+                    published += 1;
+                }
+            });
+            match status {
+                PopStatus::Empty | PopStatus::Pending => {
+                    if published > 0 {
+                        let _ = publisher.drained();
+                    }
+                    return Ok(published);
+                }
+                PopStatus::Consumed | PopStatus::Abandoned | PopStatus::Corrupt => {}
+            }
+        }
+    }
+
+    /// Drain the queue until `stop` is set
+    pub fn run(&mut self, stop: &AtomicBool) -> io::Result<()> {
+        while !stop.load(Ordering::Relaxed) {
+            if self.drain()? == 0 {
+                std::thread::sleep(self.poll_interval);
+            }
+        }
+        self.drain().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+    use crate::logger::Logger;
+    use crate::test_util::capture_publisher;
+
+    fn temp_queue_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "async-pub-{}-{}-{}",
+            name,
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    const SMALL: ShmQueueConfig = ShmQueueConfig {
+        slot_count: 4,
+        slot_size: 64,
+    };
+
+    fn pop(consumer: &mut ConsumerLock) -> (PopStatus, Vec<u8>) {
+        let mut out = Vec::new();
+        let status =
+            consumer.pop_with(Duration::from_secs(5), |bytes| out.extend_from_slice(bytes));
+        (status, out)
+    }
+
+    #[test]
+    fn push_pop_wraps_around() {
+        let path = temp_queue_path("wrap");
+        let queue = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+        let mut consumer = queue.lock_consumer().unwrap();
+
+        for i in 0..10u8 {
+            queue.push(&[i, i]).unwrap();
+            assert_eq!(pop(&mut consumer), (PopStatus::Consumed, vec![i, i]));
+        }
+        assert_eq!(pop(&mut consumer).0, PopStatus::Empty);
+
+        drop(consumer);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn full_queue_drops() {
+        let path = temp_queue_path("full");
+        let queue = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+
+        for i in 0..4u8 {
+            queue.push(&[i]).unwrap();
+        }
+        assert_eq!(queue.push(&[4]), Err(PushError::Full));
+        assert_eq!(queue.push(&[0; 64]), Err(PushError::TooLarge));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.len(), 4);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reopened_queue_resumes_after_consumer_restart() {
+        let path = temp_queue_path("resume");
+        {
+            let queue = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+            queue.push(b"a").unwrap();
+            queue.push(b"b").unwrap();
+            let mut consumer = queue.lock_consumer().unwrap();
+            assert_eq!(pop(&mut consumer), (PopStatus::Consumed, b"a".to_vec()));
+        }
+
+        let queue = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+        let mut consumer = queue.lock_consumer().unwrap();
+        assert_eq!(pop(&mut consumer), (PopStatus::Consumed, b"b".to_vec()));
+        assert!(ShmQueue::open(
+            &path,
+            ShmQueueConfig {
+                slot_count: 8,
+                slot_size: 64
+            }
+        )
+        .is_err());
+
+        drop(consumer);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn crashed_writer_is_abandoned() {
+        let path = temp_queue_path("crash");
+        let queue = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+
+        // Simulate a producer that reserved slot 0 and died before committing
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        queue.atomic_u64(HEAD_OFFSET).store(1, Ordering::Relaxed);
+        queue
+            .slot_atomic_u32(0, WRITER_PID_OFFSET)
+            .store(dead_pid, Ordering::Relaxed);
+        queue.push(b"after").unwrap();
+
+        let mut consumer = queue.lock_consumer().unwrap();
+        assert_eq!(pop(&mut consumer).0, PopStatus::Abandoned);
+        assert_eq!(pop(&mut consumer), (PopStatus::Consumed, b"after".to_vec()));
+        assert_eq!(queue.dropped(), 1);
+
+        // Slot 0 is usable again on the next lap
+        for _ in 0..4 {
+            queue.push(b"x").unwrap();
+        }
+
+        drop(consumer);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stalled_writer_waits_then_is_abandoned() {
+        let path = temp_queue_path("stall");
+        let queue = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+        // Reserved but never written, with the writer pid unknown
+        queue.atomic_u64(HEAD_OFFSET).store(1, Ordering::Relaxed);
+
+        let mut consumer = queue.lock_consumer().unwrap();
+        assert_eq!(
+            consumer.pop_with(Duration::from_millis(20), |_| {}),
+            PopStatus::Pending
+        );
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(
+            consumer.pop_with(Duration::from_millis(20), |_| {}),
+            PopStatus::Abandoned
+        );
+
+        drop(consumer);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn single_live_consumer() {
+        let path = temp_queue_path("consumer");
+        let queue = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+        let consumer = queue.lock_consumer().unwrap();
+        // Not even from this process, through the same or another mapping
+        assert!(queue.lock_consumer().is_err());
+        let reopened = Arc::new(ShmQueue::open(&path, SMALL).unwrap());
+        assert!(reopened.lock_consumer().is_err());
+        drop(consumer);
+        drop(reopened.lock_consumer().unwrap());
+
+        // Pretend another live process (this test's parent) holds the lock
+        queue
+            .atomic_u32(CONSUMER_PID_OFFSET)
+            .store(std::os::unix::process::parent_id(), Ordering::Relaxed);
+        assert!(queue.lock_consumer().is_err());
+        // The lock of an exited process with this process's pid is stale
+        queue
+            .atomic_u32(CONSUMER_PID_OFFSET)
+            .store(std::process::id(), Ordering::Relaxed);
+        assert!(queue.lock_consumer().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    static SIDECAR_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    struct TestTarget;
+
+    impl SidecarTarget for TestTarget {
+        fn path() -> PathBuf {
+            SIDECAR_PATH.lock().unwrap().clone().unwrap()
+        }
+    }
+
+    capture_publisher!(CapturePublisher, PUBLISHED, String);
+
+    #[test]
+    fn logger_writes_to_sidecar() {
+        let path = temp_queue_path("logger");
+        *SIDECAR_PATH.lock().unwrap() = Some(path.clone());
+
+        let logger: Logger<String> = Logger::new_sidecar::<TestTarget>();
+        // Stages belong to the sidecar process
+        assert!(matches!(
+            logger.add_stage(|_: &mut String, _: &mut Context| {}),
+            Err(Error::Unsupported(_))
+        ));
+        crate::context::scope(Context::new().with("request_id", "r1"), || {
+            logger.send("hello".to_string()).unwrap();
+        });
+        logger.close();
+
+        let mut sidecar =
+            Sidecar::<String, CapturePublisher>::open(&path, ShmQueueConfig::default())
+                .unwrap()
+                .redact_with(Arc::new(Redactor::new().pattern("hel+o").unwrap()));
+        assert_eq!(sidecar.drain().unwrap(), 1);
+
+        let published = PUBLISHED.lock().unwrap();
+        assert_eq!(published[0].0, "[REDACTED]");
+        assert_eq!(published[0].1.get("request_id"), Some("r1"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    struct MissingTarget;
+
+    impl SidecarTarget for MissingTarget {
+        fn path() -> PathBuf {
+            std::env::temp_dir()
+                .join("async-pub-missing-dir")
+                .join("queue")
+        }
+    }
+
+    #[test]
+    fn unopened_sidecar_reports_why_on_send() {
+        let logger: Logger<String> = Logger::new_sidecar::<MissingTarget>();
+        match logger.send("hello".to_string()) {
+            Err(Error::Transport(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::NotFound);
+                assert!(e.to_string().contains("async-pub-missing-dir"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        logger.close();
+        assert!(matches!(
+            logger.send("hello".to_string()),
+            Err(Error::Closed)
+        ));
+    }
+
+    capture_publisher!(RunPublisher, RUN_PUBLISHED, String);
+
+    #[test]
+    fn run_drains_until_stopped() {
+        let path = temp_queue_path("run");
+        let config = ShmQueueConfig::default();
+        let producer = SidecarProducer::<String>::open(&path, config).unwrap();
+        let mut sidecar = Sidecar::<String, RunPublisher>::open(&path, config)
+            .unwrap()
+            .poll_interval(Duration::from_millis(1))
+            .abandon_after(Duration::from_millis(100));
+        let stop = AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            let running = s.spawn(|| sidecar.run(&stop));
+            producer.send("a".to_string(), Context::new()).unwrap();
+            while RUN_PUBLISHED.lock().unwrap().is_empty() {
+                std::thread::yield_now();
+            }
+            stop.store(true, Ordering::Relaxed);
+            running.join().unwrap().unwrap();
+        });
+        assert!(sidecar.queue().is_empty());
+        assert_eq!(RUN_PUBLISHED.lock().unwrap()[0].0, "a");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
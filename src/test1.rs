@@ -1,14 +1,8 @@
-use crate::logger;
-use logger::UnsafeSyncCell;
+use crate::global::GlobalCell;
 
-static BGD: UnsafeSyncCell<String> = UnsafeSyncCell::new(String::new());
+static BGD: GlobalCell<String> = GlobalCell::new();
 
 pub fn main() {
-    unsafe {
-        let _ = std::mem::replace(
-            BGD.data.get().as_mut().unwrap(),
-            "Hello, world!".to_string(),
-        );
-        println!("{}", BGD.data.get().as_ref().unwrap());
-    }
+    let _ = BGD.replace("Hello, world!".to_string());
+    println!("{}", BGD.get().unwrap());
 }
@@ -1,13 +1,13 @@
-use crate::logger;
-use logger::UnsafeSyncCell;
+use std::sync::Mutex;
 
-static BGD: UnsafeSyncCell<Vec<String>> = UnsafeSyncCell::new(Vec::new());
+use crate::global::GlobalCell;
+
+static BGD: GlobalCell<Mutex<Vec<String>>> = GlobalCell::new();
 
 pub fn main() {
-    unsafe {
-        BGD.data.get().as_mut().unwrap().push("Hello, world!".to_string());
-        println!("{}", BGD.data.get().as_mut().unwrap().join(", "));
-        BGD.data.get().as_mut().unwrap().push("Hello, again!".to_string());
-        println!("{}", BGD.data.get().as_mut().unwrap().join(", "));
-    }
+    let bgd = BGD.get_or_init(|| Mutex::new(Vec::new()));
+    bgd.lock().unwrap().push("Hello, world!".to_string());
+    println!("{}", bgd.lock().unwrap().join(", "));
+    bgd.lock().unwrap().push("Hello, again!".to_string());
+    println!("{}", bgd.lock().unwrap().join(", "));
 }
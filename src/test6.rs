@@ -1,6 +1,7 @@
 use std::{thread, time};
 use rand::{rngs::ThreadRng, Rng};
 
+use crate::error::Error;
 use crate::logger::{Logger, Publisher};
 
 static BGD: Logger<u8> = Logger::new::<Pub>();
@@ -14,7 +15,7 @@ impl Publisher<u8> for Pub {
         Pub { msg_count: 0 }
     }
 
-    fn send(&mut self, data: u8) -> Result<(), ()> {
+    fn send(&mut self, data: u8) -> Result<(), Error> {
         self.msg_count += 1;
         let duration = time::Duration::from_millis(2000);
         thread::sleep(duration);
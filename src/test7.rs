@@ -2,7 +2,12 @@ use std::{thread, time};
 
 use rand::{rngs::ThreadRng, Rng};
 
-use crate::{counter::inc_counter, counter_server};
+use crate::{
+    counter::{configure, inc_counter, shutdown},
+    counter_config::CounterConfig,
+    counter_server,
+    counter_types::CounterKey,
+};
 
 fn sleep_random_millis(rng: &mut ThreadRng) {
     let millis = rng.gen_range(0..72000); // Generates a number between 0 and 20
@@ -11,22 +16,23 @@ fn sleep_random_millis(rng: &mut ThreadRng) {
 }
 
 pub fn main() {
-    thread::spawn(move || {
-        counter_server::run_server().unwrap();
-    });
-    // Give the server time to start
-    thread::sleep(time::Duration::from_millis(100));
+    let config = CounterConfig::from_env().unwrap();
+    configure(config.clone()).unwrap();
+    // Counts sent before the server is listening are buffered until it is
+    thread::spawn(move || counter_server::run_server(config).unwrap());
 
     for i in 0..330 {
         thread::spawn(move || {
             let mut rng = rand::thread_rng();
             sleep_random_millis(&mut rng);
-            inc_counter("did_a_thing".to_string());
+            crate::counter!("did_a_thing").inc().unwrap();
             if i % 10 == 0 {
-                inc_counter(format!("did_a_new_thing_{}", i));
+                inc_counter(CounterKey::new("did_a_new_thing").label("thread", i.to_string()))
+                    .unwrap();
             }
         });
     }
 
     thread::sleep(time::Duration::from_secs(70));
+    shutdown().unwrap();
 }
@@ -0,0 +1,34 @@
+// Fixtures shared by the tests of several modules
+
+/// Define a `Publisher<$data>` named `$publisher` that appends each item, with the context
+/// it was sent with, to a static `Mutex<Vec<($data, Context)>>` named `$published`.
+/// Each use gets its own static, so tests running in parallel don't see each other's items.
+macro_rules! capture_publisher {
+    ($publisher:ident, $published:ident, $data:ty) => {
+        static $published: std::sync::Mutex<Vec<($data, $crate::context::Context)>> =
+            std::sync::Mutex::new(Vec::new());
+
+        struct $publisher;
+
+        impl $crate::logger::Publisher<$data> for $publisher {
+            fn new() -> Self {
+                $publisher
+            }
+
+            fn send(&mut self, _data: $data) -> Result<(), $crate::error::Error> {
+                unreachable!("send_with_context is overridden")
+            }
+
+            fn send_with_context(
+                &mut self,
+                data: $data,
+                context: $crate::context::Context,
+            ) -> Result<(), $crate::error::Error> {
+                $published.lock().unwrap().push((data, context));
+                Ok(())
+            }
+        }
+    };
+}
+
+pub(crate) use capture_publisher;